@@ -0,0 +1,145 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use std::time::Duration;
+
+use super::{list, monitors, pick};
+use crate::{
+    models::{Configuration, ConfigurationFilters, ImageMeta, Monitor},
+    utils::{self, history::History, hook},
+};
+
+pub struct ApplyOptions<'a> {
+    pub hook_name: Option<&'a str>,
+    pub monitor_names: &'a [String],
+    pub per_monitor: bool,
+    pub weight: Option<&'a str>,
+    pub timeout: Duration,
+    pub dry_run: bool,
+}
+
+pub fn apply_images(
+    configuration: &Configuration,
+    filters: &ConfigurationFilters,
+    options: &ApplyOptions,
+) -> Result<()> {
+    let (hook_name, template) = get_hook(configuration, options.hook_name)?;
+    let metas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
+    let mut rng = fastrand::Rng::new();
+
+    let selections: Vec<(Option<&Monitor>, ImageMeta)> = match options.per_monitor {
+        true => {
+            let monitors = monitors::get_monitors(configuration, options.monitor_names)?;
+            monitors::select_images_for_monitors(
+                configuration,
+                metas,
+                filters,
+                &monitors,
+                &mut rng,
+            )?
+            .into_iter()
+            .map(|(monitor, meta)| (Some(monitor), meta))
+            .collect()
+        }
+        false => {
+            let metas = list::filter_image_metas(
+                &configuration.root_images_dir,
+                metas,
+                filters,
+                false,
+                false,
+            )?;
+            pick::sample_image_metas(metas, 1, options.weight, &mut rng)
+                .into_iter()
+                .map(|meta| (None, meta))
+                .collect()
+        }
+    };
+
+    if selections.is_empty() {
+        bail!("no image matches the given selectors");
+    }
+
+    // Render every command first so that a bad template does not leave monitors half applied
+    let commands = selections
+        .iter()
+        .map(|(monitor, meta)| {
+            hook::render_command(template, &hook::placeholder_values(meta, *monitor))
+        })
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| match options.per_monitor {
+            false if template.contains("{monitor}") => format!(
+                "failed to render hook `{hook_name}`, `{{monitor}}` needs --monitor or --all-monitors"
+            ),
+            _ => format!("failed to render hook `{hook_name}`"),
+        })?;
+
+    if options.dry_run {
+        for command in commands.iter() {
+            println!("{}", shell_words::join(command));
+        }
+        return Ok(());
+    }
+
+    let mut applied_metas = vec![];
+    let mut result = Ok(());
+    for ((_, meta), command) in selections.into_iter().zip(commands.iter()) {
+        result =
+            hook::run_command(command, options.timeout).and_then(|status| match status.code() {
+                Some(0) => Ok(()),
+                Some(code) => Err(anyhow!("hook `{hook_name}` exited with code {code}")),
+                None => Err(anyhow!("hook `{hook_name}` was terminated by a signal")),
+            });
+
+        if result.is_err() {
+            break;
+        }
+
+        eprintln!("applied: {}", meta.path.display());
+        applied_metas.push(meta);
+    }
+
+    // Remember images that were applied even if a later monitor failed
+    let mut history = History::load()?;
+    history.record(&applied_metas);
+    history.save()?;
+
+    result
+}
+
+/// Get the hook command template by name, or the only configured hook when no name is given
+pub fn get_hook<'a>(
+    configuration: &'a Configuration,
+    name: Option<&'a str>,
+) -> Result<(&'a str, &'a str)> {
+    let hooks = &configuration.hooks;
+    if hooks.is_empty() {
+        bail!("no hooks configured, add a [hooks] section to the config file");
+    }
+
+    let names = || {
+        hooks
+            .keys()
+            .map(|name| format!("`{name}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    match name {
+        Some(name) => match hooks.get(name) {
+            Some(template) => Ok((name, template)),
+            None => bail!("unknown hook `{}`, expected one of: {}", name, names()),
+        },
+        None if hooks.len() == 1 => {
+            let (name, template) = hooks.iter().next().context("hooks should not be empty")?;
+            info!("using the only configured hook: {name}");
+            Ok((name, template))
+        }
+        None => bail!(
+            "several hooks configured, choose one with --hook: {}",
+            names()
+        ),
+    }
+}
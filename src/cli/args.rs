@@ -1,9 +1,17 @@
-use clap::{command, Parser, Subcommand};
-use std::{ffi::OsString, ops::RangeInclusive, path::PathBuf};
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::info;
+use std::{ffi::OsString, ops::RangeInclusive, path::PathBuf, time::Duration};
 
 use crate::{
-    models::ScoreFilter,
-    utils::common::{parse_range, parse_score_filters},
+    models::{
+        configuration::{ColorMatch, ThemeFilter},
+        image_meta::{Color, Orientation},
+        AspectFilter, Configuration, ConfigurationFilters, Query, ScoreFilter, SortKey,
+    },
+    utils::common::{
+        parse_float_range, parse_pixel_count, parse_range, parse_score_filters, parse_sort_key,
+    },
 };
 
 #[derive(Debug, Parser)]
@@ -15,8 +23,22 @@ pub struct Cli {
 
     #[command(flatten)]
     pub verbose: clap_verbosity_flag::Verbosity,
+
+    /// Ignore cached file hashes and dimensions and compute them again
+    #[arg(long, global = true)]
+    pub rehash: bool,
+
+    /// Name of the library to use. Default is the `default_library` from config
+    #[arg(short = 'L', long, global = true)]
+    pub library: Option<String>,
+
+    /// Number of worker threads used to walk directories and to hash and probe images. Default is the number of CPUs
+    #[arg(short = 'J', long, global = true)]
+    pub jobs: Option<usize>,
 }
 
+// Parsed once at startup, boxing the selectors is not worth it
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// View and manage configuration
@@ -34,36 +56,367 @@ pub enum Commands {
     /// List images that match given selectors
     #[command(name = "list", alias = "ls")]
     List {
-        /// Filter based on parent directories
-        #[arg(short = 'd', long = "directories")]
-        active_directories: Option<Vec<PathBuf>>,
+        #[command(flatten)]
+        selectors: Selectors,
+
+        #[command(flatten)]
+        sort_options: SortOptions,
+
+        #[command(flatten)]
+        random_options: RandomOptions,
+
+        /// Select one suitable image for each of these configured monitors
+        #[arg(
+            short = 'm',
+            long = "monitor",
+            conflicts_with_all = ["sort_keys", "limit", "offset", "random", "verify_dims"]
+        )]
+        monitors: Vec<String>,
+
+        /// Select one suitable image for each configured monitor
+        #[arg(
+            long = "all-monitors",
+            conflicts_with_all = ["monitors", "sort_keys", "limit", "offset", "random", "verify_dims"]
+        )]
+        all_monitors: bool,
+
+        /// List images of several libraries at once, each prefixed with its library
+        #[arg(
+            long = "libraries",
+            value_delimiter = ',',
+            conflicts_with_all = ["random", "monitors", "all_monitors"]
+        )]
+        libraries: Vec<String>,
+
+        /// List images of all configured libraries, each prefixed with its library
+        #[arg(
+            long = "all-libraries",
+            conflicts_with_all = ["libraries", "random", "monitors", "all_monitors"]
+        )]
+        all_libraries: bool,
+
+        /// Read dimensions from image files instead of metadata and warn when they differ
+        #[arg(long = "verify-dims")]
+        verify_dims: bool,
+
+        /// Output in JSON
+        #[arg(short = 'j', long = "json")]
+        use_json_format: bool,
+    },
+    /// Randomly pick images that match given selectors
+    Pick {
+        #[command(flatten)]
+        selectors: Selectors,
+
+        /// Number of images to pick
+        #[arg(short = 'n', long = "count", default_value_t = 1)]
+        count: usize,
+
+        /// Name of a score used to make higher scored images more likely to be picked
+        #[arg(short = 'w', long = "weight")]
+        weight: Option<String>,
+
+        /// Seed of the random generator, for reproducible picks
+        #[arg(long = "seed")]
+        seed: Option<u64>,
+
+        /// Output in JSON
+        #[arg(short = 'j', long = "json")]
+        use_json_format: bool,
+    },
+    /// Pick an image that matches given selectors and set it as wallpaper using a configured hook
+    Apply {
+        #[command(flatten)]
+        selectors: Selectors,
+
+        /// Name of the hook command to run. Can be omitted when only one hook is configured
+        #[arg(long = "hook")]
+        hook: Option<String>,
+
+        /// Apply one suitable image to each of these configured monitors
+        #[arg(short = 'm', long = "monitor")]
+        monitors: Vec<String>,
+
+        /// Apply one suitable image to each configured monitor
+        #[arg(long = "all-monitors", conflicts_with = "monitors")]
+        all_monitors: bool,
 
-        /// Filter based on score range
-        #[arg(short = 's', long = "scores", value_parser = parse_score_filters)]
-        scores: Option<Vec<ScoreFilter>>,
+        /// Name of a score used to make higher scored images more likely to be picked
+        #[arg(short = 'w', long = "weight", conflicts_with_all = ["monitors", "all_monitors"])]
+        weight: Option<String>,
 
-        /// Filter based on width range
-        #[arg(short = 'W', long = "width", value_parser = parse_range)]
-        width_range: Option<RangeInclusive<usize>>,
+        /// Kill the hook command if it is still running after this duration
+        #[arg(long = "timeout", value_parser = humantime::parse_duration, default_value = "30s")]
+        timeout: Duration,
 
-        /// Filter based on height range
-        #[arg(short = 'H', long = "height", value_parser = parse_range)]
-        height_range: Option<RangeInclusive<usize>>,
+        /// Only print commands that would be run. Does not run anything
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Periodically set a random image that matches given selectors as wallpaper
+    Daemon {
+        #[command(flatten)]
+        selectors: Selectors,
+
+        /// Duration between two wallpaper changes, e.g. `15m`
+        #[arg(long = "interval", value_parser = humantime::parse_duration, default_value = "15m")]
+        interval: Duration,
+
+        /// Command template to run for each image, e.g. `swww img {path}`
+        #[arg(short = 'e', long = "exec", conflicts_with = "hook")]
+        exec: Option<String>,
+
+        /// Name of the configured hook command to run. Can be omitted when only one hook is configured
+        #[arg(long = "hook")]
+        hook: Option<String>,
+
+        /// Kill the command if it is still running after this duration
+        #[arg(long = "timeout", value_parser = humantime::parse_duration, default_value = "30s")]
+        timeout: Duration,
+    },
+    /// Find groups of visually identical images that match given selectors
+    Dupes {
+        #[command(flatten)]
+        selectors: Selectors,
+
+        /// Maximum number of differing perceptual hash bits (out of 64) between similar images
+        #[arg(long = "threshold", default_value_t = 5)]
+        threshold: u32,
+
+        /// Output in JSON
+        #[arg(short = 'j', long = "json")]
+        use_json_format: bool,
+    },
+    /// Rank images that match given selectors by visual similarity to a given image
+    Similar {
+        /// Metadata ID or path of the image file
+        identifier: OsString,
+
+        #[command(flatten)]
+        selectors: Selectors,
 
-        /// Ignore selectors preset from config
-        #[arg(short = 'i', long = "ignore")]
-        ignore_config: bool,
+        /// Number of similar images to print
+        #[arg(short = 'n', long = "count", default_value_t = 10)]
+        count: usize,
 
         /// Output in JSON
         #[arg(short = 'j', long = "json")]
         use_json_format: bool,
     },
+    /// View and manage the history of randomly selected images
+    History {
+        #[command(subcommand)]
+        command: HistoryCommands,
+    },
     /// Scan the entire images directory to find missing data
     Scan {
         /// Output in JSON
         #[arg(short = 'j', long = "json")]
         use_json_format: bool,
+
+        /// Update the metadata file with moved, new and deleted images
+        #[arg(short = 'a', long = "apply")]
+        apply: bool,
+
+        /// Only print changes that would be applied. Does not write to file system
+        #[arg(short, long, requires = "apply")]
+        dry_run: bool,
+
+        /// What to do with the metadata of deleted images when applying
+        #[arg(long = "on-deleted", value_enum, default_value_t = DeletedAction::Keep)]
+        on_deleted: DeletedAction,
+
+        /// What to do with byte-identical copies of an image when applying. The oldest copy is kept
+        #[arg(
+            long = "dedupe",
+            value_enum,
+            default_value_t = DedupeAction::Report,
+            requires_ifs = [("hardlink", "apply"), ("delete-newer", "apply")]
+        )]
+        dedupe: DedupeAction,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DedupeAction {
+    /// Only report duplicates
+    Report,
+    /// Replace copies with hard links to the kept file
+    Hardlink,
+    /// Delete copies that are newer than the kept file
+    DeleteNewer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DeletedAction {
+    /// Remove metadata from the metadata file
+    Remove,
+    /// Leave metadata untouched
+    Keep,
+    /// Move metadata to the archive file next to the metadata file
+    Archive,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Selectors {
+    /// Filter based on parent directories
+    #[arg(short = 'd', long = "directories")]
+    pub active_directories: Option<Vec<PathBuf>>,
+
+    /// Filter based on score range
+    #[arg(short = 's', long = "scores", value_parser = parse_score_filters)]
+    pub scores: Option<Vec<ScoreFilter>>,
+
+    /// Filter based on width range
+    #[arg(short = 'W', long = "width", value_parser = parse_range)]
+    pub width_range: Option<RangeInclusive<usize>>,
+
+    /// Filter based on height range
+    #[arg(short = 'H', long = "height", value_parser = parse_range)]
+    pub height_range: Option<RangeInclusive<usize>>,
+
+    /// Only keep images that have all of these tags
+    #[arg(short = 't', long = "tag", value_delimiter = ',')]
+    pub tags: Option<Vec<String>>,
+
+    /// Only keep images that have at least one of these tags
+    #[arg(long = "any-tag", value_delimiter = ',')]
+    pub any_tags: Option<Vec<String>>,
+
+    /// Exclude images that have any of these tags
+    #[arg(long = "no-tag", value_delimiter = ',')]
+    pub excluded_tags: Option<Vec<String>>,
+
+    /// Filter based on aspect ratio, with an optional tolerance, e.g. `16:9` or `16:9±0.05`
+    #[arg(short = 'a', long = "aspect")]
+    pub aspect: Option<AspectFilter>,
+
+    /// Filter based on aspect ratio range (width / height), e.g. `1.5..1.8`
+    #[arg(long = "aspect-range", value_parser = parse_float_range)]
+    pub aspect_range: Option<RangeInclusive<f64>>,
+
+    /// Filter based on mean perceived luminance, from 0 (black) to 1 (white), e.g. `0.6..`
+    #[arg(long = "luminance", value_parser = parse_float_range)]
+    pub luminance_range: Option<RangeInclusive<f64>>,
+
+    /// Filter based on orientation
+    #[arg(short = 'o', long = "orientation", value_enum)]
+    pub orientation: Option<Orientation>,
+
+    /// Filter based on minimum number of pixels (width * height), e.g. `2073600` or `2M`
+    #[arg(long = "min-pixels", value_parser = parse_pixel_count)]
+    pub min_pixels: Option<u64>,
+
+    /// Filter based on color theme
+    #[arg(long = "theme", value_enum)]
+    pub theme: Option<ThemeFilter>,
+
+    /// Filter based on dominant colors
+    #[arg(short = 'c', long = "color", value_enum, value_delimiter = ',')]
+    pub colors: Option<Vec<Color>>,
+
+    /// Whether images must have any or all of the colors. Default is: any
+    #[arg(long = "color-match", value_enum)]
+    pub color_match: Option<ColorMatch>,
+
+    /// Filter using a query expression, e.g. `(score.favs >= 3 or tag:pinned) and not tag:nsfw`
+    #[arg(long = "query")]
+    pub query: Option<Query>,
+
+    /// Use the filters of a named preset from config instead of the default ones
+    #[arg(short = 'p', long = "preset")]
+    pub preset: Option<String>,
+
+    /// Ignore selectors preset from config
+    #[arg(short = 'i', long = "ignore", conflicts_with = "preset")]
+    pub ignore_config: bool,
+}
+
+impl Selectors {
+    /// Build the filters to apply, using the preset or config filters for unset selectors unless
+    /// ignored
+    pub fn resolve(self, configuration: &Configuration) -> Result<ConfigurationFilters> {
+        let filters = ConfigurationFilters {
+            active_directories: self.active_directories,
+            scores: self.scores,
+            width_range: self.width_range,
+            height_range: self.height_range,
+            tags: self.tags,
+            any_tags: self.any_tags,
+            excluded_tags: self.excluded_tags,
+            theme: self.theme,
+            colors: self.colors,
+            color_match: self.color_match,
+            aspect: self.aspect,
+            aspect_range: self.aspect_range,
+            luminance_range: self.luminance_range,
+            orientation: self.orientation,
+            min_pixels: self.min_pixels,
+            query: self.query,
+        };
+
+        if self.ignore_config {
+            info!("ignore_config flag has been added");
+            return Ok(filters);
+        }
+
+        let Some(preset) = self.preset else {
+            return Ok(filters.or(configuration.filters.clone()));
+        };
+
+        match configuration.presets.get(&preset) {
+            Some(preset_filters) => {
+                info!("using preset: {preset}");
+                Ok(filters.or(preset_filters.clone()))
+            }
+            None => bail!(
+                "unknown preset `{}`, see `kanumi config presets` for available presets",
+                preset
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct SortOptions {
+    /// Sort by comma separated keys: path, title, width, height, area, ratio, mtime, size or score.NAME, each with an optional :asc or :desc suffix
+    #[arg(long = "sort", value_parser = parse_sort_key, value_delimiter = ',')]
+    pub sort_keys: Vec<SortKey>,
+
+    /// Maximum number of images to output
+    #[arg(short = 'n', long = "limit")]
+    pub limit: Option<usize>,
+
+    /// Number of images to skip before output
+    #[arg(long = "offset", default_value_t = 0)]
+    pub offset: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct RandomOptions {
+    /// Randomly select this number of images, avoiding recently selected ones
+    #[arg(short = 'r', long = "random")]
+    pub random: Option<usize>,
+
+    /// Avoid images returned by the last N random selections
+    #[arg(long = "avoid-last", default_value_t = 10, requires = "random")]
+    pub avoid_last: usize,
+
+    /// Avoid images randomly selected within this duration, e.g. `12h`
+    #[arg(long = "avoid-within", value_parser = humantime::parse_duration, requires = "random")]
+    pub avoid_within: Option<Duration>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum HistoryCommands {
+    /// Print images previously selected at random
+    Show {
+        /// Output in JSON
+        #[arg(short = 'j', long = "json")]
+        use_json_format: bool,
     },
+    /// Forget all previously selected images
+    Clear,
 }
 
 #[derive(Debug, Subcommand)]
@@ -74,6 +427,12 @@ pub enum ConfigurationCommands {
         #[arg(short = 'j', long = "json")]
         use_json_format: bool,
     },
+    /// Print named filter presets
+    Presets {
+        /// Output in JSON
+        #[arg(short = 'j', long = "json")]
+        use_json_format: bool,
+    },
     /// Generate a default configuration file
     #[command(visible_alias = "gen")]
     Generate {
@@ -83,6 +442,8 @@ pub enum ConfigurationCommands {
     },
 }
 
+// Same as `Commands`, not worth boxing
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 pub enum MetadataCommands {
     /// Print all metadatas and exit
@@ -101,12 +462,12 @@ pub enum MetadataCommands {
         #[arg(short = 'j', long = "json")]
         use_json_format: bool,
     },
-    /// Update the metadata for an image
+    /// Update the metadata for an image using a JSON merge patch
     Edit {
         /// Metadata ID or path of the image file
         identifier: OsString,
 
-        /// Updated metadata string in JSON format
+        /// JSON merge patch (RFC 7396) applied onto the current metadata
         payload: OsString,
     },
     /// Generate default metadata for a given image
@@ -119,7 +480,40 @@ pub enum MetadataCommands {
         #[arg(short, long)]
         dry_run: bool,
     },
-    // /// Generate metadata file based on configured images directory
-    // #[command(visible_alias = "gen-meta")]
-    // GenerateMetadata { image: PathBuf },
+    /// Compute dominant colors, luminance and perceptual hash of images that match given selectors
+    Analyze {
+        #[command(flatten)]
+        selectors: Selectors,
+
+        /// Also replace colors and theme that are already set
+        #[arg(long = "force")]
+        force: bool,
+
+        /// Only print analyzed metadata. Does not write to file system
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Replace the start of absolute image paths, e.g. after moving the library
+    Rebase {
+        /// Previous location of the images
+        #[arg(long = "from")]
+        from: PathBuf,
+
+        /// New location of the images. Default is the root images directory
+        #[arg(long = "to")]
+        to: Option<PathBuf>,
+
+        /// Only print paths that would be changed. Does not write to file system
+        #[arg(short, long)]
+        dry_run: bool,
+    },
+    /// Generate metadata for all images of a directory that do not have any yet
+    Init {
+        /// Directory to walk. Relative paths start from the root images directory. Default is the root images directory
+        directory: Option<PathBuf>,
+
+        /// Only print generated metadata. Does not write to file system
+        #[arg(short, long)]
+        dry_run: bool,
+    },
 }
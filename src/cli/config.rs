@@ -1,5 +1,6 @@
 use anyhow::Result;
 use log::info;
+use std::collections::BTreeMap;
 
 use super::ConfigurationCommands;
 use crate::{models::Configuration, utils};
@@ -26,6 +27,21 @@ pub fn handle_config_command(
             }
             Ok(())
         }
+        ConfigurationCommands::Presets { use_json_format } => {
+            match use_json_format {
+                true => {
+                    let json_presets = serde_json::to_string(&configuration.presets)?;
+                    println!("{json_presets}");
+                }
+                false => {
+                    // Same layout as in the config file
+                    let presets = BTreeMap::from([("presets", &configuration.presets)]);
+                    let toml_presets = toml::to_string(&presets)?;
+                    print!("{toml_presets}");
+                }
+            }
+            Ok(())
+        }
         ConfigurationCommands::Generate { dry_run: _ } => {
             info!("generating default config...");
             let default_config = Configuration::create_default();
@@ -0,0 +1,236 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::{apply, args::Selectors, list, pick};
+use crate::{
+    models::{Configuration, ConfigurationFilters, ImageMeta},
+    utils::{self, history::History, hook},
+};
+
+/// How often signals are checked while waiting for the next change
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct DaemonOptions {
+    pub library: Option<String>,
+    pub selectors: Selectors,
+    pub interval: Duration,
+    pub exec: Option<String>,
+    pub hook_name: Option<String>,
+    pub timeout: Duration,
+}
+
+/// Config and metadata files, reloaded whenever their modification time changes
+struct DaemonState {
+    config_file: PathBuf,
+    config_mtime: Option<SystemTime>,
+    metadata_mtime: Option<SystemTime>,
+    configuration: Configuration,
+    filters: ConfigurationFilters,
+    /// Hook command template, checked whenever the configuration is loaded
+    template: String,
+    metas: Vec<ImageMeta>,
+}
+
+pub fn run_daemon(configuration: Configuration, options: DaemonOptions) -> Result<()> {
+    let terminate = Arc::new(AtomicBool::new(false));
+    let advance = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGTERM, Arc::clone(&terminate))?;
+    signal_hook::flag::register(SIGINT, Arc::clone(&terminate))?;
+    signal_hook::flag::register(SIGUSR1, Arc::clone(&advance))?;
+
+    // Fail early on a missing hook or a malformed template instead of at the first change
+    let mut state = DaemonState::new(configuration, &options)?;
+
+    eprintln!(
+        "changing wallpaper every {}, send SIGUSR1 (pid {}) to change it now",
+        humantime::format_duration(options.interval),
+        std::process::id()
+    );
+
+    let mut rng = fastrand::Rng::new();
+    let mut shown_ids: HashSet<String> = HashSet::new();
+    let mut last_id = None;
+    while !terminate.load(Ordering::Relaxed) {
+        if let Err(e) = state.reload_if_changed(&options) {
+            utils::common::print_warning(format!(
+                "failed to reload, keeping previous configuration: {e:#}"
+            ));
+        }
+
+        // Errors only skip this change, the daemon keeps running until terminated
+        match select_next(&state, &mut shown_ids, last_id.as_deref(), &mut rng) {
+            Ok(Some(meta)) => {
+                if let Err(e) = apply_image(&state.template, &meta, options.timeout) {
+                    utils::common::print_warning(format!("{e:#}"));
+                }
+
+                shown_ids.insert(meta.id.clone());
+                last_id = Some(meta.id);
+            }
+            Ok(None) => utils::common::print_warning("no image matches the given selectors"),
+            Err(e) => utils::common::print_warning(format!("failed to select an image: {e:#}")),
+        }
+
+        let deadline = Instant::now() + options.interval;
+        while Instant::now() < deadline && !terminate.load(Ordering::Relaxed) {
+            if advance.swap(false, Ordering::Relaxed) {
+                info!("received SIGUSR1, changing wallpaper now");
+                break;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    eprintln!("received termination signal, exiting");
+    Ok(())
+}
+
+/// Randomly pick an image that was not shown yet in the current round. Once every matching image
+/// has been shown, a new round starts, avoiding the image currently shown when possible
+fn select_next(
+    state: &DaemonState,
+    shown_ids: &mut HashSet<String>,
+    last_id: Option<&str>,
+    rng: &mut fastrand::Rng,
+) -> Result<Option<ImageMeta>> {
+    let candidates = list::filter_image_metas(
+        &state.configuration.root_images_dir,
+        state.metas.clone(),
+        &state.filters,
+        false,
+        false,
+    )?;
+
+    let (mut fresh, shown): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|meta| !shown_ids.contains(&meta.id));
+
+    if fresh.is_empty() {
+        if !shown.is_empty() {
+            info!(
+                "all {} matching images shown, starting a new round",
+                shown.len()
+            );
+        }
+        shown_ids.clear();
+        fresh = match shown.len() {
+            0 | 1 => shown,
+            _ => shown
+                .into_iter()
+                .filter(|meta| Some(meta.id.as_str()) != last_id)
+                .collect(),
+        };
+    }
+
+    debug!("{} images left in the current round", fresh.len());
+    Ok(pick::sample_image_metas(fresh, 1, None, rng).pop())
+}
+
+fn apply_image(template: &str, meta: &ImageMeta, timeout: Duration) -> Result<()> {
+    let command = hook::render_command(template, &hook::placeholder_values(meta, None))?;
+    run_hook(&command, timeout)?;
+    eprintln!("applied: {}", meta.path.display());
+
+    let mut history = History::load()?;
+    history.record(std::slice::from_ref(meta));
+    history
+        .save()
+        .context("failed to record the applied image in history")
+}
+
+/// Get the command template and check that it only uses placeholders available without monitor
+fn get_template(configuration: &Configuration, options: &DaemonOptions) -> Result<String> {
+    let template = match &options.exec {
+        Some(exec) => exec.clone(),
+        None => {
+            let (_, template) = apply::get_hook(configuration, options.hook_name.as_deref())?;
+            template.to_string()
+        }
+    };
+
+    let available: Vec<&str> = hook::PLACEHOLDERS
+        .into_iter()
+        .filter(|name| *name != "monitor")
+        .collect();
+    hook::check_template(&template, &available)
+        .with_context(|| format!("invalid command: `{template}`"))?;
+    Ok(template)
+}
+
+fn run_hook(command: &[String], timeout: Duration) -> Result<()> {
+    let status = hook::run_command(command, timeout)?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => bail!("command exited with code {code}"),
+        None => bail!("command was terminated by a signal"),
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl DaemonState {
+    fn new(configuration: Configuration, options: &DaemonOptions) -> Result<DaemonState> {
+        let config_file = utils::common::get_config_file()?;
+        let metas = utils::common::load_image_metas(
+            &configuration.metadata_path,
+            &configuration.root_images_dir,
+        )?;
+
+        Ok(DaemonState {
+            config_mtime: modified_time(&config_file),
+            metadata_mtime: modified_time(&configuration.metadata_path),
+            config_file,
+            filters: options.selectors.clone().resolve(&configuration)?,
+            template: get_template(&configuration, options)?,
+            configuration,
+            metas,
+        })
+    }
+
+    fn reload_if_changed(&mut self, options: &DaemonOptions) -> Result<()> {
+        let config_mtime = modified_time(&self.config_file);
+        if config_mtime != self.config_mtime {
+            self.config_mtime = config_mtime;
+            eprintln!("config file changed, reloading");
+            // Check everything before replacing anything, so a bad edit keeps the previous state
+            let mut configuration = utils::common::load_config(self.config_file.clone())?;
+            configuration.use_library(options.library.as_deref())?;
+            let filters = options.selectors.clone().resolve(&configuration)?;
+            let template = get_template(&configuration, options)?;
+
+            // Force reloading metadata when its location changed
+            if configuration.metadata_path != self.configuration.metadata_path {
+                self.metadata_mtime = None;
+            }
+            self.configuration = configuration;
+            self.filters = filters;
+            self.template = template;
+        }
+
+        let metadata_mtime = modified_time(&self.configuration.metadata_path);
+        if metadata_mtime != self.metadata_mtime {
+            self.metadata_mtime = metadata_mtime;
+            eprintln!("metadata file changed, reloading");
+            self.metas = utils::common::load_image_metas(
+                &self.configuration.metadata_path,
+                &self.configuration.root_images_dir,
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::Result;
+use log::info;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use super::list;
+use crate::{
+    models::{Configuration, ConfigurationFilters, ImageMeta},
+    utils::{self, analysis},
+};
+
+#[derive(Debug, Serialize)]
+struct DuplicateImage {
+    /// Perceptual hash distance to the best image of the group
+    distance: u32,
+    metadata: ImageMeta,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicateGroup {
+    /// Path of the image with the highest resolution
+    best: String,
+    images: Vec<DuplicateImage>,
+}
+
+pub fn find_duplicates(
+    configuration: &Configuration,
+    filters: &ConfigurationFilters,
+    threshold: u32,
+    use_json_format: bool,
+) -> Result<()> {
+    let metas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
+    let metas =
+        list::filter_image_metas(&configuration.root_images_dir, metas, filters, false, false)?;
+
+    let (hashed, unhashed): (Vec<_>, Vec<_>) = metas
+        .into_iter()
+        .partition(|meta| meta.perceptual_hash().is_some());
+    if !unhashed.is_empty() {
+        utils::common::print_warning(format!(
+            "{} images have no perceptual hash, try `kanumi metadata analyze`",
+            unhashed.len()
+        ));
+    }
+
+    // Images without any horizontal change, such as solid colors, all hash to the same value
+    let (hashed, flat): (Vec<_>, Vec<_>) = hashed
+        .into_iter()
+        .partition(|meta| !is_flat_hash(meta.perceptual_hash().unwrap_or_default()));
+    if !flat.is_empty() {
+        utils::common::print_warning(format!(
+            "skipping {} images too uniform to compare, such as solid colors",
+            flat.len()
+        ));
+    }
+
+    info!(
+        "comparing {} images with threshold {}",
+        hashed.len(),
+        threshold
+    );
+    let groups = group_similar_images(hashed, threshold);
+
+    match use_json_format {
+        true => {
+            info!("outputting as json");
+            let groups_json = serde_json::to_string(&groups)?;
+            println!("{groups_json}");
+        }
+        false => {
+            for group in groups.iter() {
+                for (index, image) in group.images.iter().enumerate() {
+                    let marker = if index == 0 { "*" } else { " " };
+                    println!(
+                        "{} {}x{}\t{}\t{}",
+                        marker,
+                        image.metadata.width,
+                        image.metadata.height,
+                        image.distance,
+                        image.metadata.path.display()
+                    );
+                }
+                println!();
+            }
+            eprintln!("found {} groups of similar images", groups.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a perceptual hash carries no information, all its bits being equal
+fn is_flat_hash(phash: u64) -> bool {
+    phash == 0 || phash == u64::MAX
+}
+
+/// Group images whose perceptual hashes differ by at most `threshold` bits from the best image
+/// of the group, the one with the highest resolution. Images are sorted by resolution, highest
+/// first, and each image joins the first group whose best image is close enough
+fn group_similar_images(mut metas: Vec<ImageMeta>, threshold: u32) -> Vec<DuplicateGroup> {
+    metas.sort_by_key(|meta| std::cmp::Reverse(meta.pixel_count()));
+    let hashes: Vec<u64> = metas
+        .iter()
+        .map(|meta| meta.perceptual_hash().unwrap_or_default())
+        .collect();
+
+    let mut group_indexes: Vec<Option<usize>> = vec![None; metas.len()];
+    let mut members: Vec<Vec<usize>> = vec![];
+    for best in 0..metas.len() {
+        if group_indexes[best].is_some() {
+            continue;
+        }
+
+        let similar: Vec<usize> = (best + 1..metas.len())
+            .into_par_iter()
+            .filter(|&index| {
+                group_indexes[index].is_none()
+                    && analysis::hamming_distance(hashes[best], hashes[index]) <= threshold
+            })
+            .collect();
+        if similar.is_empty() {
+            continue;
+        }
+
+        for &index in similar.iter().chain([&best]) {
+            group_indexes[index] = Some(members.len());
+        }
+        members.push([best].into_iter().chain(similar).collect());
+    }
+
+    let mut metas: Vec<Option<ImageMeta>> = metas.into_iter().map(Some).collect();
+    let mut groups: Vec<DuplicateGroup> = members
+        .into_iter()
+        .map(|indexes| {
+            let best_hash = hashes[indexes[0]];
+            let images: Vec<DuplicateImage> = indexes
+                .iter()
+                .map(|&index| DuplicateImage {
+                    distance: analysis::hamming_distance(best_hash, hashes[index]),
+                    metadata: metas[index]
+                        .take()
+                        .expect("each image is in a single group"),
+                })
+                .collect();
+
+            DuplicateGroup {
+                best: images[0].metadata.path.display().to_string(),
+                images,
+            }
+        })
+        .collect();
+
+    groups.sort_by(|a, b| a.best.cmp(&b.best));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashed(path: &str, width: u32, phash: u64) -> ImageMeta {
+        let mut meta = ImageMeta::for_tests(path, width, width);
+        meta.phash = Some(format!("{phash:016x}"));
+        meta
+    }
+
+    fn group_paths(groups: &[DuplicateGroup]) -> Vec<Vec<String>> {
+        groups
+            .iter()
+            .map(|group| {
+                group
+                    .images
+                    .iter()
+                    .map(|image| image.metadata.path.display().to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_start_with_highest_resolution() {
+        let metas = vec![
+            hashed("small.png", 10, 0b1),
+            hashed("big.png", 30, 0b11),
+            hashed("other.png", 20, u64::MAX),
+        ];
+
+        let groups = group_similar_images(metas, 1);
+
+        assert_eq!(group_paths(&groups), vec![vec!["big.png", "small.png"]]);
+        assert_eq!(groups[0].images[1].distance, 1);
+    }
+
+    #[test]
+    fn grouping_is_not_transitive() {
+        // a-b and b-c are within the threshold, a-c is not
+        let metas = vec![
+            hashed("a.png", 30, 0b000),
+            hashed("b.png", 20, 0b001),
+            hashed("c.png", 10, 0b011),
+        ];
+
+        let groups = group_similar_images(metas, 1);
+
+        assert_eq!(group_paths(&groups), vec![vec!["a.png", "b.png"]]);
+    }
+
+    #[test]
+    fn flat_hashes_are_detected() {
+        assert!(is_flat_hash(0));
+        assert!(is_flat_hash(u64::MAX));
+        assert!(!is_flat_hash(0b1));
+    }
+}
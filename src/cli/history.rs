@@ -0,0 +1,38 @@
+use anyhow::Result;
+use log::info;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::HistoryCommands;
+use crate::utils::history::History;
+
+pub fn handle_history_command(command: HistoryCommands) -> Result<()> {
+    let mut history = History::load()?;
+
+    match command {
+        HistoryCommands::Show { use_json_format } => {
+            match use_json_format {
+                true => {
+                    info!("outputting as json");
+                    let history_json = serde_json::to_string(&history.entries)?;
+                    println!("{history_json}");
+                }
+                false => {
+                    for entry in history.entries.iter() {
+                        let time = UNIX_EPOCH + Duration::from_secs(entry.timestamp);
+                        println!(
+                            "{}\t{}",
+                            humantime::format_rfc3339_seconds(time),
+                            entry.path.display()
+                        );
+                    }
+                }
+            }
+            Ok(())
+        }
+        HistoryCommands::Clear => {
+            info!("clearing history...");
+            history.clear();
+            history.save()
+        }
+    }
+}
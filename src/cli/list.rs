@@ -1,64 +1,273 @@
-use anyhow::Result;
-use log::info;
+use anyhow::{ensure, Result};
+use log::{info, warn};
+use serde::Serialize;
 use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
     ops::RangeInclusive,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use crate::{models::ScoreFilter, utils};
+use super::{pick, RandomOptions, SortOptions};
+use crate::{
+    models::{
+        configuration::ColorMatch, sort_key::SortField, Configuration, ConfigurationFilters,
+        ImageMeta, SortKey,
+    },
+    utils::{self, cache::FileCache},
+};
 
 pub fn list_images_using_metadata(
-    root_images_dir: &Path,
-    metadata_path: &Path,
-    active_directories: Option<Vec<PathBuf>>,
-    score_filters: Option<Vec<ScoreFilter>>,
-    width_range: Option<RangeInclusive<usize>>,
-    height_range: Option<RangeInclusive<usize>>,
+    configuration: &Configuration,
+    filters: &ConfigurationFilters,
+    sort_options: &SortOptions,
+    random_options: &RandomOptions,
     use_json_format: bool,
+    verify_dims: bool,
+    rehash: bool,
 ) -> Result<()> {
-    let metas = utils::common::load_image_metas(metadata_path)?;
+    let metas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
+    let mut filtered_metas = filter_image_metas(
+        &configuration.root_images_dir,
+        metas,
+        filters,
+        verify_dims,
+        rehash,
+    )?;
 
-    info!("active_directories: {:?}", active_directories);
-    info!("score_filters: {:?}", score_filters);
-    info!("width_range: {:?}", width_range);
-    info!("height_range: {:?}", height_range);
+    if let Some(count) = random_options.random {
+        info!("selecting {} random images", count);
+        filtered_metas = pick::sample_image_metas_with_history(
+            filtered_metas,
+            count,
+            random_options.avoid_last,
+            random_options.avoid_within,
+            &mut fastrand::Rng::new(),
+        )?;
+    }
+
+    if !sort_options.sort_keys.is_empty() {
+        info!("sorting by: {:?}", sort_options.sort_keys);
+        sort_image_metas(&mut filtered_metas, &sort_options.sort_keys);
+    }
+
+    let filtered_metas: Vec<_> = filtered_metas
+        .into_iter()
+        .skip(sort_options.offset)
+        .take(sort_options.limit.unwrap_or(usize::MAX))
+        .collect();
+
+    match use_json_format {
+        true => {
+            info!("outputting as json");
+            let metas_json = serde_json::to_string(&filtered_metas)?;
+            println!("{}", metas_json);
+        }
+        false => {
+            info!("outputting image paths only");
+            for meta in filtered_metas.iter() {
+                println!("{}", meta.path.display());
+            }
+        }
+    };
+
+    Ok(())
+}
+
+/// Image metadata along with the name of the library it belongs to
+#[derive(Debug, Serialize)]
+struct LibraryImageMeta {
+    library: String,
+    metadata: ImageMeta,
+}
+
+impl AsRef<ImageMeta> for LibraryImageMeta {
+    fn as_ref(&self) -> &ImageMeta {
+        &self.metadata
+    }
+}
+
+/// List images of several libraries at once. All libraries are used if `library_names` is empty
+pub fn list_images_across_libraries(
+    configuration: &Configuration,
+    library_names: &[String],
+    filters: &ConfigurationFilters,
+    sort_options: &SortOptions,
+    use_json_format: bool,
+    verify_dims: bool,
+    rehash: bool,
+) -> Result<()> {
+    let library_names: Vec<&str> = match library_names.is_empty() {
+        true => configuration.library_names(),
+        false => library_names.iter().map(String::as_str).collect(),
+    };
 
     let mut filtered_metas = vec![];
+    for name in library_names {
+        let library = configuration.get_library(name)?;
+        ensure!(
+            library.metadata_path.exists(),
+            "could not find metadata file of library `{}`: {}",
+            name,
+            library.metadata_path.display()
+        );
 
-    if let Some(active_dirs) = active_directories {
-        for active_dir in active_dirs.iter() {
-            info!("filter using active directory: {:?}", active_dir);
-            let matching_metas: Vec<_> = metas
-                .iter()
-                .filter(|meta| {
-                    let base_directory = if active_dir.is_absolute() {
-                        active_dir.clone()
-                    } else {
-                        root_images_dir.join(active_dir)
-                    };
-
-                    meta.path
-                        .ancestors()
-                        .any(|ancestor| ancestor == base_directory)
-                })
-                .cloned()
-                .collect();
-
-            filtered_metas.extend(matching_metas);
+        info!("listing images of library: {name}");
+        let metas =
+            utils::common::load_image_metas(&library.metadata_path, &library.root_images_dir)?;
+        let metas = filter_image_metas(
+            &library.root_images_dir,
+            metas,
+            filters,
+            verify_dims,
+            rehash,
+        )?;
+        filtered_metas.extend(metas.into_iter().map(|metadata| LibraryImageMeta {
+            library: name.to_string(),
+            metadata,
+        }));
+    }
+
+    if !sort_options.sort_keys.is_empty() {
+        info!("sorting by: {:?}", sort_options.sort_keys);
+        sort_image_metas(&mut filtered_metas, &sort_options.sort_keys);
+    }
+
+    let filtered_metas: Vec<_> = filtered_metas
+        .into_iter()
+        .skip(sort_options.offset)
+        .take(sort_options.limit.unwrap_or(usize::MAX))
+        .collect();
+
+    match use_json_format {
+        true => {
+            info!("outputting as json");
+            let metas_json = serde_json::to_string(&filtered_metas)?;
+            println!("{}", metas_json);
+        }
+        false => {
+            for meta in filtered_metas.iter() {
+                println!("{}\t{}", meta.library, meta.metadata.path.display());
+            }
         }
+    };
+
+    Ok(())
+}
+
+/// Keep only the metadata matching all given filters
+pub fn filter_image_metas(
+    root_images_dir: &Path,
+    metas: Vec<ImageMeta>,
+    filters: &ConfigurationFilters,
+    verify_dims: bool,
+    rehash: bool,
+) -> Result<Vec<ImageMeta>> {
+    info!("filters: {:?}", filters);
+
+    let mut filtered_metas = metas;
+
+    if let Some(active_dirs) = &filters.active_directories {
+        info!("filter using active directories: {:?}", active_dirs);
+        let base_directories: Vec<PathBuf> = active_dirs
+            .iter()
+            .map(|active_dir| match active_dir.is_absolute() {
+                true => active_dir.clone(),
+                false => root_images_dir.join(active_dir),
+            })
+            .collect();
+
+        // Retain instead of collecting per directory, so overlapping directories do not
+        // produce duplicates
+        filtered_metas.retain(|meta| {
+            meta.path
+                .ancestors()
+                .any(|ancestor| base_directories.iter().any(|dir| ancestor == dir))
+        });
     } else {
-        info!("no active_dirs filters provided, setting filtered_metas to full list");
-        filtered_metas.extend(metas);
+        info!("no active_dirs filters provided, keeping full list");
     }
 
-    if width_range.is_some() || height_range.is_some() {
+    if verify_dims {
+        info!("verifying stored dimensions...");
+        let mut cache = FileCache::load(rehash)?;
+        let image_paths: Vec<&Path> = filtered_metas.iter().map(|m| m.path.as_path()).collect();
+        cache.prefetch(&image_paths, false, true, None);
+
+        for meta in filtered_metas.iter_mut() {
+            let (width, height) = match cache.get_dims(&meta.path) {
+                Ok(dimensions) => dimensions,
+                Err(e) => {
+                    warn!(
+                        "failed to check dimensions for: {}: {}",
+                        meta.path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            if (width, height) != (meta.width, meta.height) {
+                utils::common::print_warning(format!(
+                    "stale dimensions for {}: stored {}x{}, actual {}x{}",
+                    meta.path.display(),
+                    meta.width,
+                    meta.height,
+                    width,
+                    height
+                ));
+                meta.width = width;
+                meta.height = height;
+            }
+        }
+        cache.save()?;
+    }
+
+    if filters.width_range.is_some() || filters.height_range.is_some() {
         info!("applying dimensions filter...");
         filtered_metas.retain(|meta| {
-            utils::common::image_matches_dims(&meta.path, &width_range, &height_range)
+            utils::common::dims_match(
+                (meta.width, meta.height),
+                &filters.width_range,
+                &filters.height_range,
+            )
+        });
+    }
+
+    if let Some(aspect) = &filters.aspect {
+        info!("applying aspect filter...");
+        filtered_metas.retain(|meta| aspect.matches(meta.aspect_ratio()));
+    }
+
+    if let Some(aspect_range) = &filters.aspect_range {
+        info!("applying aspect range filter...");
+        filtered_metas.retain(|meta| aspect_range.contains(&meta.aspect_ratio()));
+    }
+
+    if let Some(luminance_range) = &filters.luminance_range {
+        info!("applying luminance filter...");
+        filtered_metas.retain(|meta| {
+            meta.luminance
+                .is_some_and(|luminance| luminance_range.contains(&(luminance.mean as f64)))
         });
     }
 
-    if let Some(score_filters) = score_filters {
+    if let Some(orientation) = &filters.orientation {
+        info!("applying orientation filter...");
+        filtered_metas.retain(|meta| meta.orientation() == *orientation);
+    }
+
+    if let Some(min_pixels) = filters.min_pixels {
+        info!("applying min pixels filter...");
+        filtered_metas.retain(|meta| meta.pixel_count() >= min_pixels);
+    }
+
+    if let Some(score_filters) = &filters.scores {
         info!("applying image meta score filters...");
 
         for score_filter in score_filters.iter() {
@@ -66,23 +275,94 @@ pub fn list_images_using_metadata(
         }
     }
 
-    match use_json_format {
-        true => {
-            info!("outputting as json");
-            let metas_json = serde_json::to_string(&filtered_metas)?;
-            println!("{}", metas_json);
+    if let Some(tags) = &filters.tags {
+        info!("applying all of tags filter...");
+        filtered_metas.retain(|meta| tags.iter().all(|tag| meta.tags.contains(tag)));
+    }
+
+    if let Some(any_tags) = &filters.any_tags {
+        info!("applying any of tags filter...");
+        filtered_metas.retain(|meta| any_tags.iter().any(|tag| meta.tags.contains(tag)));
+    }
+
+    if let Some(excluded_tags) = &filters.excluded_tags {
+        info!("applying excluded tags filter...");
+        filtered_metas.retain(|meta| !excluded_tags.iter().any(|tag| meta.tags.contains(tag)));
+    }
+
+    if let Some(theme) = &filters.theme {
+        info!("applying theme filter...");
+        filtered_metas.retain(|meta| theme.matches(meta.theme));
+    }
+
+    if let Some(colors) = &filters.colors {
+        info!("applying colors filter...");
+        let color_match = filters.color_match.unwrap_or(ColorMatch::Any);
+        filtered_metas.retain(|meta| match color_match {
+            ColorMatch::Any => colors.iter().any(|color| meta.colors.contains(color)),
+            ColorMatch::All => colors.iter().all(|color| meta.colors.contains(color)),
+        });
+    }
+
+    if let Some(query) = &filters.query {
+        info!("applying query filter...");
+        filtered_metas.retain(|meta| query.matches(meta));
+    }
+
+    Ok(filtered_metas)
+}
+
+/// Sort metadata by the given keys, in order of priority
+pub fn sort_image_metas<T: AsRef<ImageMeta>>(metas: &mut [T], sort_keys: &[SortKey]) {
+    let mut file_stats: HashMap<PathBuf, (Option<SystemTime>, Option<u64>)> = HashMap::new();
+    if sort_keys.iter().any(|key| key.field.needs_file_stat()) {
+        for meta in metas.iter().map(T::as_ref) {
+            let stat = fs::metadata(&meta.path)
+                .inspect_err(|e| warn!("failed to stat {}: {}", meta.path.display(), e))
+                .ok();
+            let mtime = stat.as_ref().and_then(|stat| stat.modified().ok());
+            let size = stat.as_ref().map(|stat| stat.len());
+            file_stats.insert(meta.path.clone(), (mtime, size));
         }
-        false => {
-            info!("outputting image paths only");
-            for meta in filtered_metas.iter() {
-                println!("{}", meta.path.display());
+    }
+
+    let score_of = |meta: &ImageMeta, name: &str| {
+        meta.scores
+            .iter()
+            .find(|score| score.name == name)
+            .map(|score| score.value)
+    };
+
+    metas.sort_by(|a, b| {
+        let (a, b) = (a.as_ref(), b.as_ref());
+        for key in sort_keys.iter() {
+            let ordering = match &key.field {
+                SortField::Path => a.path.cmp(&b.path),
+                SortField::Title => a.title.cmp(&b.title),
+                SortField::Width => a.width.cmp(&b.width),
+                SortField::Height => a.height.cmp(&b.height),
+                SortField::Area => a.pixel_count().cmp(&b.pixel_count()),
+                SortField::AspectRatio => a.aspect_ratio().total_cmp(&b.aspect_ratio()),
+                SortField::Score(name) => score_of(a, name).cmp(&score_of(b, name)),
+                SortField::ModifiedTime => file_stats[&a.path].0.cmp(&file_stats[&b.path].0),
+                SortField::Size => file_stats[&a.path].1.cmp(&file_stats[&b.path].1),
+            };
+
+            let ordering = match key.descending {
+                true => ordering.reverse(),
+                false => ordering,
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
             }
         }
-    };
 
-    Ok(())
+        Ordering::Equal
+    });
 }
 
+#[allow(dead_code)]
 fn filter_images_without_using_metadata(
     base_directory: PathBuf,
     width_range: Option<RangeInclusive<usize>>,
@@ -96,7 +376,11 @@ fn filter_images_without_using_metadata(
 
     if width_range.is_some() || height_range.is_some() {
         info!("applying dimensions filter...");
-        images.retain(|img| utils::common::image_matches_dims(img, &width_range, &height_range));
+        let mut cache = FileCache::load(false)?;
+        images.retain(|img| {
+            utils::common::image_matches_dims(img, &mut cache, &width_range, &height_range)
+        });
+        cache.save()?;
     }
 
     for image in images.iter() {
@@ -1,52 +1,67 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use serde_json::Value;
 use std::{
+    collections::{HashMap, HashSet},
     ffi::OsString,
     path::{Path, PathBuf},
-    result,
 };
 
 use crate::{
-    models::{Configuration, ImageMeta},
-    utils,
+    models::{Configuration, ConfigurationFilters, ImageMeta},
+    utils::{self, analysis, cache::FileCache, progress::Progress, store::MetadataStore},
 };
 
-use super::MetadataCommands;
+use super::{list, MetadataCommands};
 
 pub fn handle_metadata_command(
     command: MetadataCommands,
     configuration: &Configuration,
+    rehash: bool,
 ) -> Result<()> {
-    let metadatas = utils::common::load_image_metas(&configuration.metadata_path)?;
+    let metadatas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
 
     match command {
         MetadataCommands::Show => {
             let metas_json = serde_json::to_string(&metadatas)?;
-            Ok(println!("{metas_json}"))
+            println!("{metas_json}");
+            Ok(())
+        }
+        MetadataCommands::Get { identifier } => {
+            get_metadata(&identifier, &metadatas, &configuration.root_images_dir)
         }
-        MetadataCommands::Get { identifier } => get_metadata(&identifier, &metadatas),
         MetadataCommands::Edit {
             identifier,
             payload,
-        } => update_metadata(&identifier, &payload, &metadatas),
+        } => update_metadata(&identifier, &payload, configuration),
         MetadataCommands::Generate { image, dry_run: _ } => {
             info!("generating default metadata...");
-            let meta = ImageMeta::create_from_image(&image)?;
+            let mut cache = FileCache::load(rehash)?;
+            let mut meta = ImageMeta::create_from_image(&image, &mut cache)?;
+            cache.save()?;
+            analysis::analyze_image(&image, &configuration.theme_detection)?
+                .apply_to(&mut meta, true);
             let json = serde_json::to_string(&meta)?;
             println!("{}", json);
             Ok(())
-
-            // let images = get_all_images(&base_dir)?;
-            // let mut metadatas = vec![];
-            // for image in images.iter() {
-            //     let meta = ImageMeta::create_from_image(image)?;
-            //     metadatas.push(meta);
-            // }
-            //
-            // let json = serde_json::to_string(&metadatas)?;
-            // println!("{}", json);
-            // Ok(())
+        }
+        MetadataCommands::Analyze {
+            selectors,
+            force,
+            dry_run,
+        } => {
+            let filters = selectors.resolve(configuration)?;
+            analyze_metadata(&filters, configuration, force, dry_run)
+        }
+        MetadataCommands::Rebase { from, to, dry_run } => {
+            rebase_metadata(&from, to, configuration, dry_run)
+        }
+        MetadataCommands::Init { directory, dry_run } => {
+            init_metadata(directory, configuration, dry_run, rehash)
         }
         MetadataCommands::Search {
             query,
@@ -123,21 +138,295 @@ fn search_metadata(query: OsString, metadatas: &[ImageMeta]) -> Result<Option<&I
 fn update_metadata(
     identifier: &OsString,
     payload: &OsString,
-    metadatas: &[ImageMeta],
+    configuration: &Configuration,
 ) -> Result<()> {
+    let store = MetadataStore::open(&configuration.metadata_path, &configuration.root_images_dir)?;
+    let mut metadatas = store.load()?;
+
     let identifier = identifier.to_string_lossy();
-    let meta = utils::common::get_image_by_path_or_id(&identifier, metadatas)?;
-    if meta.is_none() {
-        bail!("no matching metadata for: {identifier}");
+    // Several entries can share an id, so keep track of the one that matched
+    let index = match utils::common::get_image_index_by_path_or_id(
+        &identifier,
+        &metadatas,
+        &configuration.root_images_dir,
+    )? {
+        Some(index) => index,
+        None => bail!("no matching metadata for: {identifier}"),
+    };
+    let meta = &metadatas[index];
+
+    let patch: Value = serde_json::from_str(&payload.to_string_lossy())
+        .context("payload should be a valid JSON document")?;
+    ensure!(
+        patch.is_object(),
+        "payload should be a JSON object, got: {patch}"
+    );
+
+    let mut merged = serde_json::to_value(meta)?;
+    utils::common::apply_merge_patch(&mut merged, &patch);
+    debug!("merged metadata: {merged}");
+
+    let updated: ImageMeta = serde_json::from_value(merged.clone())
+        .context("patched metadata does not match the metadata format")?;
+    ensure!(
+        updated.id == meta.id,
+        "metadata id cannot be changed: `{}` -> `{}`",
+        meta.id,
+        updated.id
+    );
+
+    let known_fields = serde_json::to_value(&updated)?;
+    let unknown_fields: Vec<_> = merged
+        .as_object()
+        .context("merged metadata should be a JSON object")?
+        .keys()
+        .filter(|key| known_fields.get(key.as_str()).is_none())
+        .cloned()
+        .collect();
+    ensure!(
+        unknown_fields.is_empty(),
+        "payload contains unknown metadata fields: {}",
+        unknown_fields.join(", ")
+    );
+
+    info!("updating metadata for: {}", updated.path.display());
+    metadatas[index] = updated;
+    store.save(&metadatas)?;
+
+    let meta_json = serde_json::to_string(&metadatas[index])?;
+    println!("{meta_json}");
+    Ok(())
+}
+
+fn init_metadata(
+    directory: Option<PathBuf>,
+    configuration: &Configuration,
+    dry_run: bool,
+    rehash: bool,
+) -> Result<()> {
+    let directory = match directory {
+        Some(dir) if dir.is_relative() => configuration.root_images_dir.join(dir),
+        Some(dir) => dir,
+        None => configuration.root_images_dir.clone(),
+    };
+    ensure!(
+        directory.is_dir(),
+        "could not find directory: {}",
+        directory.display()
+    );
+
+    let store = match dry_run {
+        true => None,
+        false => Some(MetadataStore::open(
+            &configuration.metadata_path,
+            &configuration.root_images_dir,
+        )?),
+    };
+    let mut metadatas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
+
+    info!("about to run WalkDir on {}", directory.display());
+    let known_paths: HashSet<PathBuf> = metadatas.iter().map(|m| m.path.clone()).collect();
+    let images: Vec<PathBuf> = utils::common::get_all_images(&directory)?
+        .into_iter()
+        .filter(|image| !known_paths.contains(image))
+        .collect();
+
+    eprintln!("found {} images without metadata", images.len());
+
+    let mut cache = FileCache::load(rehash)?;
+    let image_paths: Vec<&Path> = images.iter().map(PathBuf::as_path).collect();
+    let progress = Progress::new("hashing", image_paths.len());
+    cache.prefetch(&image_paths, true, true, Some(&progress));
+
+    let mut known_ids: HashSet<String> = metadatas.iter().map(|m| m.id.clone()).collect();
+    let mut generated = vec![];
+    let mut failed_count = 0;
+    let mut skipped_count = 0;
+    for image in images.iter() {
+        let meta = match ImageMeta::create_from_image(image, &mut cache) {
+            Ok(meta) => meta,
+            Err(e) => {
+                warn!(
+                    "failed to generate metadata for: {}: {}",
+                    image.display(),
+                    e
+                );
+                failed_count += 1;
+                continue;
+            }
+        };
+
+        if !known_ids.insert(meta.id.clone()) {
+            warn!(
+                "skipping {}: metadata with the same id already exists, try `kanumi scan --apply`",
+                image.display()
+            );
+            skipped_count += 1;
+            continue;
+        }
+
+        generated.push(meta);
+    }
+
+    cache.save()?;
+
+    let progress = Progress::new("analyzing", generated.len());
+    analysis::analyze_image_metas(
+        &mut generated,
+        &configuration.theme_detection,
+        true,
+        Some(&progress),
+    );
+
+    let summary = format!(
+        "{} generated, {} skipped (same content as existing metadata), {} failed",
+        generated.len(),
+        skipped_count,
+        failed_count
+    );
+    let Some(store) = store else {
+        let json = serde_json::to_string(&generated)?;
+        println!("{json}");
+        eprintln!("dry run, would apply: {summary}");
+        return Ok(());
+    };
+
+    metadatas.extend(generated);
+    store.save(&metadatas)?;
+    eprintln!("done: {summary}");
+
+    Ok(())
+}
+
+fn analyze_metadata(
+    filters: &ConfigurationFilters,
+    configuration: &Configuration,
+    force: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let store = match dry_run {
+        true => None,
+        false => Some(MetadataStore::open(
+            &configuration.metadata_path,
+            &configuration.root_images_dir,
+        )?),
+    };
+    let mut metadatas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
+
+    let mut selected = list::filter_image_metas(
+        &configuration.root_images_dir,
+        metadatas.clone(),
+        filters,
+        false,
+        false,
+    )?;
+
+    let progress = Progress::new("analyzing", selected.len());
+    let failed_count = analysis::analyze_image_metas(
+        &mut selected,
+        &configuration.theme_detection,
+        force,
+        Some(&progress),
+    );
+
+    let summary = format!(
+        "{} analyzed, {} failed",
+        selected.len() - failed_count,
+        failed_count
+    );
+    let Some(store) = store else {
+        let json = serde_json::to_string(&selected)?;
+        println!("{json}");
+        eprintln!("dry run, would apply: {summary}");
+        return Ok(());
+    };
+
+    // Several entries may share an id when files have the same content, paths are unique
+    let mut analyzed: HashMap<PathBuf, ImageMeta> = selected
+        .into_iter()
+        .map(|meta| (meta.path.clone(), meta))
+        .collect();
+    for meta in metadatas.iter_mut() {
+        if let Some(analyzed_meta) = analyzed.remove(&meta.path) {
+            *meta = analyzed_meta;
+        }
+    }
+    store.save(&metadatas)?;
+    eprintln!("done: {summary}");
+
+    Ok(())
+}
+
+fn rebase_metadata(
+    from: &Path,
+    to: Option<PathBuf>,
+    configuration: &Configuration,
+    dry_run: bool,
+) -> Result<()> {
+    let to = to.unwrap_or_else(|| configuration.root_images_dir.clone());
+    let store = match dry_run {
+        true => None,
+        false => Some(MetadataStore::open(
+            &configuration.metadata_path,
+            &configuration.root_images_dir,
+        )?),
+    };
+    let mut metadatas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
+
+    let mut rebased_count = 0;
+    for meta in metadatas.iter_mut() {
+        let Ok(rest) = meta.path.strip_prefix(from) else {
+            continue;
+        };
+
+        let new_path = to.join(rest);
+        if dry_run {
+            println!("{}\t{}", meta.path.display(), new_path.display());
+        }
+        debug!("rebase: {} -> {}", meta.path.display(), new_path.display());
+        meta.path = new_path;
+        rebased_count += 1;
     }
 
-    let meta = meta.unwrap();
-    todo!()
+    let missing_count = metadatas.iter().filter(|meta| !meta.path.exists()).count();
+    if missing_count > 0 {
+        warn!("{missing_count} metadatas point to missing files, try `kanumi scan`");
+    }
+
+    let summary = format!(
+        "{} rebased, {} unchanged, {} pointing to missing files",
+        rebased_count,
+        metadatas.len() - rebased_count,
+        missing_count
+    );
+    let Some(store) = store else {
+        eprintln!("dry run, would apply: {summary}");
+        return Ok(());
+    };
+
+    // Saving also stores paths relative to the root images directory
+    store.save(&metadatas)?;
+    eprintln!("done: {summary}");
+
+    Ok(())
 }
 
-fn get_metadata(identifier: &OsString, metadatas: &[ImageMeta]) -> Result<()> {
+fn get_metadata(
+    identifier: &OsString,
+    metadatas: &[ImageMeta],
+    root_images_dir: &Path,
+) -> Result<()> {
     let identifier = identifier.to_string_lossy();
-    match utils::common::get_image_by_path_or_id(&identifier, metadatas)? {
+    match utils::common::get_image_by_path_or_id(&identifier, metadatas, root_images_dir)? {
         Some(meta) => {
             let meta_json = serde_json::to_string(meta)?;
             println!("{meta_json}");
@@ -1,14 +1,36 @@
+pub mod apply;
 pub mod args;
 pub mod config;
+pub mod daemon;
+pub mod dupes;
+pub mod history;
 pub mod list;
 pub mod metadata;
+pub mod monitors;
+pub mod pick;
 pub mod scan;
+pub mod similar;
 
+pub use self::apply::apply_images;
+pub use self::apply::ApplyOptions;
 pub use self::args::Cli;
 pub use self::args::Commands;
 pub use self::args::ConfigurationCommands;
+pub use self::args::DedupeAction;
+pub use self::args::DeletedAction;
+pub use self::args::HistoryCommands;
 pub use self::args::MetadataCommands;
+pub use self::args::RandomOptions;
+pub use self::args::SortOptions;
 pub use self::config::handle_config_command;
+pub use self::daemon::run_daemon;
+pub use self::daemon::DaemonOptions;
+pub use self::dupes::find_duplicates;
+pub use self::history::handle_history_command;
+pub use self::list::list_images_across_libraries;
 pub use self::list::list_images_using_metadata;
 pub use self::metadata::handle_metadata_command;
+pub use self::monitors::list_images_for_monitors;
+pub use self::pick::pick_images;
 pub use self::scan::scan_images;
+pub use self::similar::find_similar_images;
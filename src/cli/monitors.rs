@@ -0,0 +1,121 @@
+use anyhow::{bail, ensure, Result};
+use log::info;
+use serde_json::json;
+use std::collections::HashSet;
+
+use super::{list, pick};
+use crate::{
+    models::{Configuration, ConfigurationFilters, ImageMeta, Monitor},
+    utils,
+};
+
+pub fn list_images_for_monitors(
+    configuration: &Configuration,
+    filters: &ConfigurationFilters,
+    monitor_names: &[String],
+    use_json_format: bool,
+) -> Result<()> {
+    let monitors = get_monitors(configuration, monitor_names)?;
+    let metas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
+    let selections = select_images_for_monitors(
+        configuration,
+        metas,
+        filters,
+        &monitors,
+        &mut fastrand::Rng::new(),
+    )?;
+
+    match use_json_format {
+        true => {
+            info!("outputting as json");
+            let selections: Vec<_> = selections
+                .iter()
+                .map(|(monitor, meta)| json!({ "monitor": monitor.name, "metadata": meta }))
+                .collect();
+            let selections_json = serde_json::to_string(&selections)?;
+            println!("{selections_json}");
+        }
+        false => {
+            for (monitor, meta) in selections.iter() {
+                println!("{}\t{}", monitor.name, meta.path.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Get monitors by name from the configuration. All monitors are returned if `names` is empty
+pub fn get_monitors<'a>(
+    configuration: &'a Configuration,
+    names: &[String],
+) -> Result<Vec<&'a Monitor>> {
+    ensure!(
+        !configuration.monitors.is_empty(),
+        "no monitors configured, add a [[monitors]] section to the config file"
+    );
+
+    if names.is_empty() {
+        return Ok(configuration.monitors.iter().collect());
+    }
+
+    let mut monitors = vec![];
+    for name in names.iter() {
+        match configuration.monitors.iter().find(|m| m.name == *name) {
+            Some(monitor) => monitors.push(monitor),
+            None => bail!("unknown monitor: `{name}`"),
+        }
+    }
+
+    Ok(monitors)
+}
+
+/// Randomly select one image per monitor, avoiding the same image on several monitors when
+/// possible. Monitors without any suitable image are skipped with a warning
+pub fn select_images_for_monitors<'a>(
+    configuration: &Configuration,
+    metas: Vec<ImageMeta>,
+    filters: &ConfigurationFilters,
+    monitors: &[&'a Monitor],
+    rng: &mut fastrand::Rng,
+) -> Result<Vec<(&'a Monitor, ImageMeta)>> {
+    let root_images_dir = &configuration.root_images_dir;
+    let candidates = list::filter_image_metas(root_images_dir, metas, filters, false, false)?;
+
+    let mut used_ids = HashSet::new();
+    let mut selections = vec![];
+    for monitor in monitors.iter() {
+        info!("selecting image for monitor: {}", monitor.name);
+        let monitor_metas = list::filter_image_metas(
+            root_images_dir,
+            candidates.clone(),
+            &monitor.to_filters(),
+            false,
+            false,
+        )?;
+
+        let (unused_metas, used_metas): (Vec<_>, Vec<_>) = monitor_metas
+            .into_iter()
+            .partition(|meta| !used_ids.contains(&meta.id));
+
+        let selected = pick::sample_image_metas(unused_metas, 1, None, rng)
+            .pop()
+            .or_else(|| pick::sample_image_metas(used_metas, 1, None, rng).pop());
+
+        match selected {
+            Some(meta) => {
+                used_ids.insert(meta.id.clone());
+                selections.push((*monitor, meta));
+            }
+            None => utils::common::print_warning(format!(
+                "no suitable image for monitor: {}",
+                monitor.name
+            )),
+        }
+    }
+
+    Ok(selections)
+}
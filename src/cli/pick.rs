@@ -0,0 +1,183 @@
+use anyhow::Result;
+use log::info;
+use std::{path::Path, time::Duration};
+
+use super::list;
+use crate::{
+    models::{ConfigurationFilters, ImageMeta},
+    utils::{self, history::History},
+};
+
+pub fn pick_images(
+    root_images_dir: &Path,
+    metadata_path: &Path,
+    filters: &ConfigurationFilters,
+    count: usize,
+    weight: Option<&str>,
+    seed: Option<u64>,
+    use_json_format: bool,
+) -> Result<()> {
+    let metas = utils::common::load_image_metas(metadata_path, root_images_dir)?;
+    let filtered_metas = list::filter_image_metas(root_images_dir, metas, filters, false, false)?;
+
+    let mut rng = match seed {
+        Some(seed) => fastrand::Rng::with_seed(seed),
+        None => fastrand::Rng::new(),
+    };
+
+    info!("picking {} out of {} images", count, filtered_metas.len());
+    let picked_metas = sample_image_metas(filtered_metas, count, weight, &mut rng);
+
+    match use_json_format {
+        true => {
+            info!("outputting as json");
+            let metas_json = serde_json::to_string(&picked_metas)?;
+            println!("{}", metas_json);
+        }
+        false => {
+            info!("outputting image paths only");
+            for meta in picked_metas.iter() {
+                println!("{}", meta.path.display());
+            }
+        }
+    };
+
+    Ok(())
+}
+
+/// Randomly pick up to `count` images, avoiding images recently selected according to the
+/// history, then record the picked images in the history
+pub fn sample_image_metas_with_history(
+    metas: Vec<ImageMeta>,
+    count: usize,
+    avoid_last: usize,
+    avoid_within: Option<Duration>,
+    rng: &mut fastrand::Rng,
+) -> Result<Vec<ImageMeta>> {
+    let mut history = History::load()?;
+    let recent_ids = history.recent_ids(avoid_last, avoid_within);
+    let (fresh_metas, mut recent_metas): (Vec<_>, Vec<_>) = metas
+        .into_iter()
+        .partition(|meta| !recent_ids.contains(&meta.id));
+
+    info!(
+        "{} fresh images, {} recently selected",
+        fresh_metas.len(),
+        recent_metas.len()
+    );
+    let mut picked_metas = sample_image_metas(fresh_metas, count, None, rng);
+
+    if picked_metas.len() < count {
+        info!("not enough fresh images, reusing the least recently selected ones");
+        recent_metas.sort_by_key(|meta| history.last_selected_index(&meta.id));
+        let missing_count = count - picked_metas.len();
+        picked_metas.extend(recent_metas.into_iter().take(missing_count));
+    }
+
+    history.record(&picked_metas);
+    history.save()?;
+    Ok(picked_metas)
+}
+
+/// Randomly pick up to `count` distinct images.
+///
+/// When `weight` names a score, an image with a score value of `v` is `v + 1` times more likely to
+/// be picked than an image without that score.
+pub fn sample_image_metas(
+    metas: Vec<ImageMeta>,
+    count: usize,
+    weight: Option<&str>,
+    rng: &mut fastrand::Rng,
+) -> Vec<ImageMeta> {
+    // Weighted sampling without replacement (Efraimidis-Spirakis): keep the `count` highest
+    // `u^(1/w)` keys, which is equivalent to a uniform shuffle when all weights are equal
+    let mut keyed_metas: Vec<(f64, ImageMeta)> = metas
+        .into_iter()
+        .map(|meta| {
+            let weight = match weight {
+                Some(name) => meta
+                    .scores
+                    .iter()
+                    .find(|score| score.name == name)
+                    .map_or(1.0, |score| f64::from(score.value) + 1.0),
+                None => 1.0,
+            };
+
+            (rng.f64().powf(1.0 / weight), meta)
+        })
+        .collect();
+
+    keyed_metas.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed_metas
+        .into_iter()
+        .take(count)
+        .map(|(_, meta)| meta)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::image_meta::ImageScore;
+
+    fn metas(count: usize) -> Vec<ImageMeta> {
+        (0..count)
+            .map(|index| ImageMeta::for_tests(&format!("{index}.png"), 1920, 1080))
+            .collect()
+    }
+
+    fn ids(metas: &[ImageMeta]) -> Vec<&str> {
+        metas.iter().map(|meta| meta.id.as_str()).collect()
+    }
+
+    #[test]
+    fn same_seed_picks_same_images() {
+        let first = sample_image_metas(metas(50), 5, None, &mut fastrand::Rng::with_seed(42));
+        let second = sample_image_metas(metas(50), 5, None, &mut fastrand::Rng::with_seed(42));
+        assert_eq!(ids(&first), ids(&second));
+
+        let other = sample_image_metas(metas(50), 5, None, &mut fastrand::Rng::with_seed(7));
+        assert_ne!(ids(&first), ids(&other));
+    }
+
+    #[test]
+    fn picks_distinct_images_up_to_count() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let picked = sample_image_metas(metas(10), 4, None, &mut rng);
+        let mut picked_ids = ids(&picked);
+        picked_ids.sort();
+        picked_ids.dedup();
+        assert_eq!(picked_ids.len(), 4);
+
+        assert_eq!(sample_image_metas(metas(3), 10, None, &mut rng).len(), 3);
+        assert!(sample_image_metas(vec![], 1, None, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn higher_scores_are_picked_more_often() {
+        let mut favorite = ImageMeta::for_tests("favorite.png", 1920, 1080);
+        favorite.scores.push(ImageScore {
+            name: "favorite".to_string(),
+            value: 9,
+        });
+        let other = ImageMeta::for_tests("other.png", 1920, 1080);
+
+        let mut rng = fastrand::Rng::with_seed(0);
+        let pick_count = |weight: Option<&str>, rng: &mut fastrand::Rng| {
+            (0..1_000)
+                .filter(|_| {
+                    let picked =
+                        sample_image_metas(vec![favorite.clone(), other.clone()], 1, weight, rng);
+                    picked[0].id == favorite.id
+                })
+                .count()
+        };
+
+        // Weight 10 against 1: picked about 10 times out of 11
+        let weighted = pick_count(Some("favorite"), &mut rng);
+        assert!((850..=960).contains(&weighted), "{weighted}");
+
+        let unweighted = pick_count(None, &mut rng);
+        assert!((430..=570).contains(&unweighted), "{unweighted}");
+    }
+}
@@ -1,19 +1,39 @@
-use anyhow::Result;
+use anyhow::{ensure, Context, Result};
 use log::{debug, info, warn};
 use serde_json::json;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
-use crate::{models::ImageMeta, utils};
+use super::{DedupeAction, DeletedAction};
+use crate::{
+    models::{Configuration, ImageMeta},
+    utils::{self, analysis, cache::FileCache, store::MetadataStore},
+};
 
 pub fn scan_images(
-    base_directory: &Path,
-    metadata_path: &Path,
+    configuration: &Configuration,
     use_json_format: bool,
+    apply_with: Option<DeletedAction>,
+    dedupe: DedupeAction,
+    dry_run: bool,
+    rehash: bool,
 ) -> Result<()> {
     info!("scanning for missing metadata or images...");
+    let base_directory = &configuration.root_images_dir;
+    let metadata_path = &configuration.metadata_path;
+
+    // Lock before reading so that nothing changes between the scan and the write
+    let store = match apply_with {
+        Some(_) if !dry_run => Some(MetadataStore::open(metadata_path, base_directory)?),
+        _ => None,
+    };
 
     info!("about to run WalkDir on {}", base_directory.display());
-    let all_metas = utils::common::load_image_metas(metadata_path)?;
+    let all_metas = utils::common::load_image_metas(metadata_path, base_directory)?;
 
     let mut mappings: HashMap<&Path, Option<ImageMeta>> = HashMap::new();
     let images = utils::common::get_all_images(base_directory)?;
@@ -28,20 +48,47 @@ pub fn scan_images(
 
     debug!("created {} img:Option<meta> mappings", mappings.len());
 
-    let mut metaless_images: HashMap<String, &Path> = HashMap::new();
-    for (img_path, metadata) in mappings.iter() {
-        if metadata.is_none() {
-            let hash = utils::common::compute_blake3_hash(img_path)?;
-            metaless_images.insert(hash, img_path);
-        }
+    let metaless_paths: Vec<&Path> = mappings
+        .iter()
+        .filter(|(_, metadata)| metadata.is_none())
+        .map(|(img_path, _)| *img_path)
+        .collect();
+
+    let mut cache = FileCache::load(rehash)?;
+    cache.prefetch(&metaless_paths, true, false, None);
+
+    // Byte-identical files share the same hash, so keep all paths of each hash
+    let mut metaless_images: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for img_path in metaless_paths.iter() {
+        let hash = cache.get_hash(img_path)?;
+        metaless_images
+            .entry(hash)
+            .or_default()
+            .push(img_path.to_path_buf());
     }
+    cache.save()?;
 
     debug!(
         "computed hash for {} images that had no metadata",
-        metaless_images.len()
+        metaless_paths.len()
     );
 
-    let mut moved_images: HashMap<&Path, &ImageMeta> = HashMap::new();
+    // Each group of duplicates is sorted oldest first, the first path being the one kept
+    let mut duplicates: Vec<Vec<PathBuf>> = vec![];
+
+    let mut known_images: HashMap<&str, Vec<PathBuf>> = HashMap::new();
+    for meta in all_metas.iter().filter(|meta| meta.path.exists()) {
+        known_images
+            .entry(meta.id.as_str())
+            .or_default()
+            .push(meta.path.clone());
+    }
+    for (id, mut paths) in known_images {
+        paths.extend(metaless_images.remove(id).unwrap_or_default());
+        duplicates.extend(group_duplicates(paths));
+    }
+
+    let mut moved_images: Vec<(PathBuf, &ImageMeta)> = vec![];
     let mut deleted_images: Vec<&ImageMeta> = vec![];
 
     for meta in all_metas.iter() {
@@ -50,25 +97,32 @@ pub fn scan_images(
         }
 
         warn!("image path invalid for: {meta:?}");
-        if let Some(image_path) = metaless_images.get(&meta.id) {
+        if let Some(paths) = metaless_images.remove(&meta.id) {
+            let paths = sort_oldest_first(paths);
             warn!(
                 "{} seems to have been moved to: {}",
                 meta.path.display(),
-                image_path.display()
+                paths[0].display()
             );
-            moved_images.insert(image_path, meta);
-            metaless_images.retain(|hash, _| *hash != meta.id);
+            moved_images.push((paths[0].clone(), meta));
+            duplicates.extend(group_duplicates(paths));
         } else {
             warn!("cannot find image: {}", meta.path.display());
             deleted_images.push(meta);
         }
     }
 
-    let new_images = metaless_images;
+    let mut new_images: Vec<PathBuf> = vec![];
+    for (_, paths) in metaless_images {
+        let paths = sort_oldest_first(paths);
+        new_images.push(paths[0].clone());
+        duplicates.extend(group_duplicates(paths));
+    }
+    new_images.sort();
+    duplicates.sort();
 
     match use_json_format {
         true => {
-            let new_images: Vec<_> = new_images.values().collect();
             let moved_images: Vec<_> = moved_images
                 .iter()
                 .map(|(new_path, meta)| {
@@ -79,10 +133,21 @@ pub fn scan_images(
                 })
                 .collect();
 
+            let duplicates: Vec<_> = duplicates
+                .iter()
+                .map(|paths| {
+                    json!({
+                        "keep": paths[0],
+                        "copies": paths[1..],
+                    })
+                })
+                .collect();
+
             let summary = json!({
                 "new": new_images,
                 "moved": moved_images,
                 "deleted": deleted_images,
+                "duplicates": duplicates,
             });
             let summary_json = serde_json::to_string(&summary)?;
             println!("{summary_json}");
@@ -90,7 +155,7 @@ pub fn scan_images(
         false => {
             if !new_images.is_empty() {
                 println!("{} new:", new_images.len());
-                for (_, img_path) in new_images.iter() {
+                for img_path in new_images.iter() {
                     println!("- {}", img_path.display());
                 }
                 println!();
@@ -111,8 +176,281 @@ pub fn scan_images(
                 }
                 println!();
             }
+
+            if !duplicates.is_empty() {
+                println!("{} duplicates:", duplicates.len());
+                for paths in duplicates.iter() {
+                    println!("- {}", paths[0].display());
+                    for copy in paths[1..].iter() {
+                        println!("  = {}", copy.display());
+                    }
+                }
+                println!();
+            }
+        }
+    }
+
+    let Some(on_deleted) = apply_with else {
+        return Ok(());
+    };
+
+    // Matched by previous path, as several entries can share an id
+    let moved_images: Vec<(PathBuf, PathBuf)> = moved_images
+        .into_iter()
+        .map(|(new_path, meta)| (new_path, meta.path.clone()))
+        .collect();
+    let deleted_paths: HashSet<PathBuf> = deleted_images.iter().map(|m| m.path.clone()).collect();
+
+    let mut updated_metas = all_metas.clone();
+    for (new_path, old_path) in moved_images.iter() {
+        if let Some(meta) = updated_metas.iter_mut().find(|m| m.path == *old_path) {
+            info!("move: {} -> {}", meta.path.display(), new_path.display());
+            meta.path = new_path.clone();
+        }
+    }
+
+    let new_paths: Vec<&Path> = new_images.iter().map(PathBuf::as_path).collect();
+    cache.prefetch(&new_paths, true, true, None);
+    let mut new_metas = vec![];
+    for image_path in new_images.iter() {
+        info!("create metadata for: {}", image_path.display());
+        match ImageMeta::create_from_image(image_path, &mut cache) {
+            Ok(meta) => new_metas.push(meta),
+            Err(e) => utils::common::print_warning(format!(
+                "skipping {}: failed to generate metadata: {:#}",
+                image_path.display(),
+                e
+            )),
+        }
+    }
+    cache.save()?;
+    let added_count = new_metas.len();
+
+    if store.is_some() {
+        analysis::analyze_image_metas(&mut new_metas, &configuration.theme_detection, true, None);
+    }
+    updated_metas.extend(new_metas);
+
+    if dedupe == DedupeAction::DeleteNewer {
+        // Metadata of a deleted copy moves to the kept file, unless it already has its own
+        for paths in duplicates.iter() {
+            let (kept, copies) = (&paths[0], &paths[1..]);
+            let mut kept_has_meta = updated_metas.iter().any(|meta| meta.path == *kept);
+            updated_metas.retain_mut(|meta| {
+                if !copies.contains(&meta.path) {
+                    return true;
+                }
+                if kept_has_meta {
+                    info!("drop metadata of deleted copy: {}", meta.path.display());
+                    return false;
+                }
+
+                info!("move: {} -> {}", meta.path.display(), kept.display());
+                meta.path = kept.clone();
+                kept_has_meta = true;
+                true
+            });
+        }
+    }
+
+    let mut archived_metas = vec![];
+    if on_deleted != DeletedAction::Keep {
+        let (removed, kept): (Vec<_>, Vec<_>) = updated_metas
+            .into_iter()
+            .partition(|meta| deleted_paths.contains(&meta.path));
+        updated_metas = kept;
+
+        if on_deleted == DeletedAction::Archive {
+            archived_metas = removed;
+        }
+    }
+
+    let deleted_label = match on_deleted {
+        DeletedAction::Remove => "removed",
+        DeletedAction::Keep => "kept",
+        DeletedAction::Archive => "archived",
+    };
+    let dedupe_label = match dedupe {
+        DedupeAction::Report => "reported",
+        DedupeAction::Hardlink => "hardlinked",
+        DedupeAction::DeleteNewer => "deleted",
+    };
+    let summary = format!(
+        "{} moved, {} added, {} deleted {}, {} duplicate copies {}",
+        moved_images.len(),
+        added_count,
+        deleted_paths.len(),
+        deleted_label,
+        duplicates
+            .iter()
+            .map(|paths| paths.len() - 1)
+            .sum::<usize>(),
+        dedupe_label
+    );
+
+    let Some(store) = store else {
+        eprintln!("dry run, would apply: {summary}");
+        return Ok(());
+    };
+
+    if !archived_metas.is_empty() {
+        let archive_path = get_archive_path(metadata_path)?;
+        let archive_store = MetadataStore::open(&archive_path, base_directory)?;
+        let mut archive = match archive_path.exists() {
+            true => archive_store.load()?,
+            false => vec![],
+        };
+
+        archive.extend(archived_metas);
+        archive_store.save(&archive)?;
+        info!("archived deleted metadata to: {}", archive_path.display());
+    }
+
+    // Metadata is saved before touching duplicates: a copy that fails to be deduplicated is
+    // only left in place, while its metadata never points to a deleted file
+    store.save(&updated_metas)?;
+
+    let mut failed_count = 0;
+    for paths in duplicates.iter() {
+        let (kept, copies) = (&paths[0], &paths[1..]);
+        for copy in copies.iter() {
+            let result = match dedupe {
+                DedupeAction::Report => Ok(()),
+                DedupeAction::Hardlink => {
+                    info!("hardlink: {} -> {}", copy.display(), kept.display());
+                    replace_with_hard_link(kept, copy)
+                }
+                DedupeAction::DeleteNewer => {
+                    info!("delete: {}", copy.display());
+                    fs::remove_file(copy)
+                        .with_context(|| format!("failed to delete: {}", copy.display()))
+                }
+            };
+
+            if let Err(e) = result {
+                utils::common::print_warning(format!("{e:#}"));
+                failed_count += 1;
+            }
         }
     }
 
+    eprintln!("applied: {summary}");
+    ensure!(
+        failed_count == 0,
+        "{failed_count} duplicate copies could not be {dedupe_label}"
+    );
+
     Ok(())
 }
+
+/// Sort byte-identical files oldest first, and keep them as a group of duplicates if there are
+/// several distinct files. Hard links to the same file are not duplicates
+fn group_duplicates(paths: Vec<PathBuf>) -> Option<Vec<PathBuf>> {
+    let mut paths = sort_oldest_first(paths);
+    let kept_inode = inode(&paths[0]);
+    let kept = paths[0].clone();
+    paths.retain(|path| *path == kept || kept_inode.is_none() || inode(path) != kept_inode);
+
+    (paths.len() > 1).then_some(paths)
+}
+
+fn sort_oldest_first(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    paths.sort_by_cached_key(|path| {
+        let mtime = fs::metadata(path)
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        (mtime, path.clone())
+    });
+    paths
+}
+
+/// Device and inode of a file, shared by hard links to the same file
+#[cfg(unix)]
+fn inode(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+/// Hard links cannot be told apart from copies here, so every file counts as distinct
+#[cfg(not(unix))]
+fn inode(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Replace `copy` with a hard link to `original`, without leaving a missing file in between
+fn replace_with_hard_link(original: &Path, copy: &Path) -> Result<()> {
+    let file_name = copy
+        .file_name()
+        .context("duplicate file should have a filename")?
+        .to_string_lossy();
+    let tmp_path = copy.with_file_name(format!(".{file_name}.kanumi-link"));
+
+    fs::hard_link(original, &tmp_path)
+        .with_context(|| format!("failed to create hard link: {}", tmp_path.display()))?;
+    if let Err(e) = fs::rename(&tmp_path, copy) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e).with_context(|| format!("failed to replace file: {}", copy.display()));
+    }
+    Ok(())
+}
+
+fn get_archive_path(metadata_path: &Path) -> Result<PathBuf> {
+    let stem = metadata_path
+        .file_stem()
+        .context("metadata file should have a filename")?
+        .to_string_lossy();
+
+    Ok(metadata_path.with_file_name(format!("{stem}.archive.json")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn removing_deleted_image_keeps_other_entries_with_same_id() {
+        let root = env::temp_dir().join(format!("kanumi-scan-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        env::set_var(utils::common::CONFIG_VAR, root.join("config"));
+
+        let image = image::RgbImage::new(2, 2);
+        image.save(root.join("kept.png")).unwrap();
+        image.save(root.join("deleted.png")).unwrap();
+
+        let mut configuration = Configuration::create_default();
+        configuration.root_images_dir = root.clone();
+        configuration.metadata_path = root.join("metadatas.json");
+
+        // Same content, so same id
+        let metas: Vec<ImageMeta> = ["deleted.png", "kept.png"]
+            .iter()
+            .map(|name| ImageMeta {
+                id: "same".to_string(),
+                ..ImageMeta::for_tests(&root.join(name).display().to_string(), 2, 2)
+            })
+            .collect();
+        MetadataStore::open(&configuration.metadata_path, &root)
+            .unwrap()
+            .save(&metas)
+            .unwrap();
+        fs::remove_file(root.join("deleted.png")).unwrap();
+
+        scan_images(
+            &configuration,
+            true,
+            Some(DeletedAction::Remove),
+            DedupeAction::Report,
+            false,
+            false,
+        )
+        .unwrap();
+
+        let metas = utils::common::load_image_metas(&configuration.metadata_path, &root).unwrap();
+        let paths: Vec<&Path> = metas.iter().map(|meta| meta.path.as_path()).collect();
+        assert_eq!(paths, [root.join("kept.png")]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
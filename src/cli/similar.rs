@@ -0,0 +1,120 @@
+use anyhow::{bail, Result};
+use log::info;
+use serde::Serialize;
+use std::ffi::OsString;
+
+use super::list;
+use crate::{
+    models::{Configuration, ConfigurationFilters, ImageMeta},
+    utils::{self, analysis},
+};
+
+/// How much each distance counts in the similarity score
+const PHASH_WEIGHT: f32 = 0.5;
+const PALETTE_WEIGHT: f32 = 0.3;
+const ASPECT_WEIGHT: f32 = 0.2;
+
+#[derive(Debug, Serialize)]
+struct SimilarImage {
+    /// Weighted distance to the reference image, from 0 (identical) to 1
+    distance: f32,
+    /// Perceptual hash distance to the reference image, if both have a perceptual hash
+    phash_distance: Option<u32>,
+    metadata: ImageMeta,
+}
+
+pub fn find_similar_images(
+    configuration: &Configuration,
+    identifier: &OsString,
+    filters: &ConfigurationFilters,
+    count: usize,
+    use_json_format: bool,
+) -> Result<()> {
+    let metas = utils::common::load_image_metas(
+        &configuration.metadata_path,
+        &configuration.root_images_dir,
+    )?;
+
+    let identifier = identifier.to_string_lossy();
+    let reference = match utils::common::get_image_by_path_or_id(
+        &identifier,
+        &metas,
+        &configuration.root_images_dir,
+    )? {
+        Some(meta) => meta.clone(),
+        None => bail!("no matching metadata for: {identifier}"),
+    };
+    info!("finding images similar to: {}", reference.path.display());
+
+    if reference.perceptual_hash().is_none() {
+        utils::common::print_warning(format!(
+            "{} has no perceptual hash, try `kanumi metadata analyze`",
+            reference.path.display()
+        ));
+    }
+
+    let candidates =
+        list::filter_image_metas(&configuration.root_images_dir, metas, filters, false, false)?;
+    let mut similar_images: Vec<SimilarImage> = candidates
+        .into_iter()
+        .filter(|meta| meta.path != reference.path)
+        .map(|meta| compare_images(&reference, meta))
+        .collect();
+
+    similar_images.sort_by(|a, b| {
+        a.distance
+            .total_cmp(&b.distance)
+            .then_with(|| a.metadata.path.cmp(&b.metadata.path))
+    });
+    similar_images.truncate(count);
+
+    match use_json_format {
+        true => {
+            info!("outputting as json");
+            let similar_json = serde_json::to_string(&similar_images)?;
+            println!("{similar_json}");
+        }
+        false => {
+            for image in similar_images.iter() {
+                println!(
+                    "{:.3}\t{}x{}\t{}",
+                    image.distance,
+                    image.metadata.width,
+                    image.metadata.height,
+                    image.metadata.path.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Combine perceptual hash, palette and aspect ratio distances. Missing analysis data counts as
+/// the largest distance
+fn compare_images(reference: &ImageMeta, meta: ImageMeta) -> SimilarImage {
+    let phash_distance = reference
+        .perceptual_hash()
+        .zip(meta.perceptual_hash())
+        .map(|(a, b)| analysis::hamming_distance(a, b));
+    let phash_score = phash_distance.map_or(1.0, |distance| distance as f32 / 64.0);
+
+    let palette_score = match reference.palette.is_empty() || meta.palette.is_empty() {
+        true => 1.0,
+        false => analysis::palette_distance(&reference.palette, &meta.palette),
+    };
+
+    // An image twice as wide relative to its height is as far as it gets
+    let aspect_score = ((reference.aspect_ratio() / meta.aspect_ratio()).ln().abs()
+        / std::f64::consts::LN_2)
+        .min(1.0) as f32;
+
+    let distance =
+        PHASH_WEIGHT * phash_score + PALETTE_WEIGHT * palette_score + ASPECT_WEIGHT * aspect_score;
+
+    SimilarImage {
+        distance: (distance * 1000.0).round() / 1000.0,
+        phash_distance,
+        metadata: meta,
+    }
+}
@@ -13,6 +13,13 @@ fn main() -> Result<()> {
         .filter_level(args.verbose.log_level_filter())
         .init();
 
+    if let Some(jobs) = args.jobs {
+        info!("using {} worker threads", jobs);
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()?;
+    }
+
     info!("process cli args");
     match process_args(args) {
         Ok(_) => Ok(()),
@@ -32,8 +39,15 @@ fn process_args(args: Cli) -> Result<()> {
     }
 
     info!("loading config");
-    let config = utils::common::load_config(config_file)?;
+    let mut config = utils::common::load_config(config_file)?;
+
+    // Configuration commands are about the whole file, not about the selected library
+    let command = match args.command {
+        Commands::Configuration { command } => return cli::handle_config_command(command, &config),
+        command => command,
+    };
 
+    config.use_library(args.library.as_deref())?;
     ensure!(
         config.root_images_dir.exists(),
         "could not find root images directory: {}",
@@ -46,46 +60,143 @@ fn process_args(args: Cli) -> Result<()> {
     );
 
     info!("metadata_path: {:?}", config.metadata_path);
-    match args.command {
+    let rehash = args.rehash;
+    match command {
         Commands::List {
-            active_directories,
-            scores,
-            width_range,
-            height_range,
-            ignore_config,
+            selectors,
+            sort_options,
+            random_options,
+            monitors,
+            all_monitors,
+            libraries,
+            all_libraries,
+            verify_dims,
             use_json_format,
         } => {
-            let mut active_directories = active_directories;
-            let mut score_filters = scores;
-            let mut width_range = width_range;
-            let mut height_range = height_range;
+            let filters = selectors.resolve(&config)?;
 
-            if !ignore_config {
-                active_directories = active_directories.or(config.filters.active_directories);
-                score_filters = score_filters.or(config.filters.scores);
-                width_range = width_range.or(config.filters.width_range);
-                height_range = height_range.or(config.filters.height_range);
-            } else {
-                info!("ignore_config flag has been added");
+            if all_libraries || !libraries.is_empty() {
+                return cli::list_images_across_libraries(
+                    &config,
+                    &libraries,
+                    &filters,
+                    &sort_options,
+                    use_json_format,
+                    verify_dims,
+                    rehash,
+                );
+            }
+
+            if all_monitors || !monitors.is_empty() {
+                return cli::list_images_for_monitors(
+                    &config,
+                    &filters,
+                    &monitors,
+                    use_json_format,
+                );
             }
 
             warn!("right now, metadata file is required to list images");
             cli::list_images_using_metadata(
+                &config,
+                &filters,
+                &sort_options,
+                &random_options,
+                use_json_format,
+                verify_dims,
+                rehash,
+            )
+        }
+        Commands::Pick {
+            selectors,
+            count,
+            weight,
+            seed,
+            use_json_format,
+        } => {
+            let filters = selectors.resolve(&config)?;
+            cli::pick_images(
                 &config.root_images_dir,
                 &config.metadata_path,
-                active_directories,
-                score_filters,
-                width_range,
-                height_range,
+                &filters,
+                count,
+                weight.as_deref(),
+                seed,
                 use_json_format,
             )
         }
-        cli::Commands::Scan { use_json_format } => cli::scan_images(
-            &config.root_images_dir,
-            &config.metadata_path,
+        Commands::Apply {
+            selectors,
+            hook,
+            monitors,
+            all_monitors,
+            weight,
+            timeout,
+            dry_run,
+        } => {
+            let filters = selectors.resolve(&config)?;
+            let options = cli::ApplyOptions {
+                hook_name: hook.as_deref(),
+                monitor_names: &monitors,
+                per_monitor: all_monitors || !monitors.is_empty(),
+                weight: weight.as_deref(),
+                timeout,
+                dry_run,
+            };
+            cli::apply_images(&config, &filters, &options)
+        }
+        Commands::Daemon {
+            selectors,
+            interval,
+            exec,
+            hook,
+            timeout,
+        } => {
+            let options = cli::DaemonOptions {
+                library: args.library,
+                selectors,
+                interval,
+                exec,
+                hook_name: hook,
+                timeout,
+            };
+            cli::run_daemon(config, options)
+        }
+        Commands::Dupes {
+            selectors,
+            threshold,
+            use_json_format,
+        } => {
+            let filters = selectors.resolve(&config)?;
+            cli::find_duplicates(&config, &filters, threshold, use_json_format)
+        }
+        Commands::Similar {
+            identifier,
+            selectors,
+            count,
             use_json_format,
+        } => {
+            let filters = selectors.resolve(&config)?;
+            cli::find_similar_images(&config, &identifier, &filters, count, use_json_format)
+        }
+        cli::Commands::Scan {
+            use_json_format,
+            apply,
+            dry_run,
+            on_deleted,
+            dedupe,
+        } => cli::scan_images(
+            &config,
+            use_json_format,
+            apply.then_some(on_deleted),
+            dedupe,
+            dry_run,
+            rehash,
         ),
-        cli::Commands::Configuration { command } => cli::handle_config_command(command, &config),
-        cli::Commands::Metadata { command } => cli::handle_metadata_command(command, &config),
+        cli::Commands::History { command } => cli::handle_history_command(command),
+        cli::Commands::Configuration { .. } => unreachable!("handled before selecting a library"),
+        cli::Commands::Metadata { command } => {
+            cli::handle_metadata_command(command, &config, rehash)
+        }
     }
 }
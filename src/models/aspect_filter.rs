@@ -0,0 +1,89 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Default tolerance used when an aspect filter does not specify one
+pub const DEFAULT_ASPECT_TOLERANCE: f64 = 0.01;
+
+/// Aspect ratio with a tolerance, written as `W:H`, `W:H±T` or `W:H+-T`, e.g. `16:9±0.05`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AspectFilter {
+    source: String,
+    pub ratio: f64,
+    pub tolerance: f64,
+}
+
+impl AspectFilter {
+    pub fn matches(&self, ratio: f64) -> bool {
+        (ratio - self.ratio).abs() <= self.tolerance
+    }
+}
+
+impl FromStr for AspectFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let (ratio_str, tolerance) = match input.split_once('±').or(input.split_once("+-")) {
+            Some((ratio, tolerance)) => {
+                let tolerance = tolerance
+                    .trim()
+                    .parse::<f64>()
+                    .with_context(|| format!("invalid aspect tolerance: `{tolerance}`"))?;
+                (ratio, tolerance)
+            }
+            None => (input, DEFAULT_ASPECT_TOLERANCE),
+        };
+
+        let ratio = match ratio_str.split_once(':') {
+            Some((width, height)) => {
+                let width = width
+                    .trim()
+                    .parse::<f64>()
+                    .with_context(|| format!("invalid aspect width: `{width}`"))?;
+                let height = height
+                    .trim()
+                    .parse::<f64>()
+                    .with_context(|| format!("invalid aspect height: `{height}`"))?;
+                if height <= 0.0 {
+                    bail!("aspect height should be > 0: `{}`", input);
+                }
+                width / height
+            }
+            None => ratio_str
+                .trim()
+                .parse::<f64>()
+                .with_context(|| format!("expected W:H or a ratio but got: `{ratio_str}`"))?,
+        };
+
+        if !ratio.is_finite() || ratio <= 0.0 || tolerance < 0.0 {
+            bail!("invalid aspect filter: `{}`", input);
+        }
+
+        Ok(AspectFilter {
+            source: input.to_string(),
+            ratio,
+            tolerance,
+        })
+    }
+}
+
+impl TryFrom<String> for AspectFilter {
+    type Error = anyhow::Error;
+
+    fn try_from(input: String) -> Result<Self> {
+        input.parse()
+    }
+}
+
+impl From<AspectFilter> for String {
+    fn from(filter: AspectFilter) -> Self {
+        filter.source
+    }
+}
+
+impl fmt::Display for AspectFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
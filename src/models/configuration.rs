@@ -1,10 +1,17 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
 use directories::UserDirs;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
-use std::{ops::RangeInclusive, path::PathBuf};
+use std::{collections::BTreeMap, ops::RangeInclusive, path::PathBuf};
 
-use super::ScoreFilter;
+use super::{
+    image_meta::{Color, ColorTheme, Orientation},
+    AspectFilter, Library, Monitor, Query, ScoreFilter,
+};
+
+/// Name of the library made of `root_path` and `meta_path`
+pub const DEFAULT_LIBRARY: &str = "default";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Configuration {
@@ -14,11 +21,46 @@ pub struct Configuration {
     #[serde(rename = "meta_path")]
     pub metadata_path: PathBuf,
 
+    /// Library used when none is given on the command line
+    #[serde(
+        rename = "default_library",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default_library: Option<String>,
+
     #[serde(rename = "filters")]
     pub filters: ConfigurationFilters,
+
+    #[serde(rename = "theme_detection", default)]
+    pub theme_detection: ThemeDetection,
+
+    #[serde(
+        rename = "libraries",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub libraries: BTreeMap<String, Library>,
+
+    #[serde(
+        rename = "presets",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub presets: BTreeMap<String, ConfigurationFilters>,
+
+    #[serde(rename = "hooks", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hooks: BTreeMap<String, String>,
+
+    #[serde(rename = "monitors", default, skip_serializing_if = "Vec::is_empty")]
+    pub monitors: Vec<Monitor>,
+
+    /// `root_path` and `meta_path` as configured, kept when `use_library` replaces them
+    #[serde(skip)]
+    top_level_library: Option<Library>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ConfigurationFilters {
     #[serde(rename = "active_dirs")]
     pub active_directories: Option<Vec<PathBuf>>,
@@ -31,6 +73,138 @@ pub struct ConfigurationFilters {
 
     #[serde(rename = "height")]
     pub height_range: Option<RangeInclusive<usize>>,
+
+    #[serde(rename = "tags")]
+    pub tags: Option<Vec<String>>,
+
+    #[serde(rename = "any_tags")]
+    pub any_tags: Option<Vec<String>>,
+
+    #[serde(rename = "no_tags")]
+    pub excluded_tags: Option<Vec<String>>,
+
+    #[serde(rename = "theme")]
+    pub theme: Option<ThemeFilter>,
+
+    #[serde(rename = "colors")]
+    pub colors: Option<Vec<Color>>,
+
+    #[serde(rename = "color_match")]
+    pub color_match: Option<ColorMatch>,
+
+    #[serde(rename = "aspect")]
+    pub aspect: Option<AspectFilter>,
+
+    #[serde(rename = "aspect_range")]
+    pub aspect_range: Option<RangeInclusive<f64>>,
+
+    #[serde(rename = "luminance")]
+    pub luminance_range: Option<RangeInclusive<f64>>,
+
+    #[serde(rename = "orientation")]
+    pub orientation: Option<Orientation>,
+
+    #[serde(rename = "min_pixels")]
+    pub min_pixels: Option<u64>,
+
+    #[serde(rename = "query")]
+    pub query: Option<Query>,
+}
+
+/// Thresholds used to classify images as light or dark, all between 0 (black) and 1 (white)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct ThemeDetection {
+    /// Images with a mean luminance below this are dark
+    #[serde(rename = "dark_below")]
+    pub dark_below: f64,
+
+    /// Images with a mean luminance above this are light. Images in between get no theme
+    #[serde(rename = "light_above")]
+    pub light_above: f64,
+
+    /// Pixels with a luminance below this count as dark in `dark_ratio`
+    #[serde(rename = "dark_pixel")]
+    pub dark_pixel: f64,
+}
+
+impl Default for ThemeDetection {
+    fn default() -> Self {
+        ThemeDetection {
+            dark_below: 0.45,
+            light_above: 0.55,
+            dark_pixel: 0.25,
+        }
+    }
+}
+
+impl ThemeDetection {
+    pub fn classify(&self, mean_luminance: f64) -> Option<ColorTheme> {
+        if mean_luminance < self.dark_below {
+            Some(ColorTheme::Dark)
+        } else if mean_luminance > self.light_above {
+            Some(ColorTheme::Light)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ThemeFilter {
+    /// Images with a light theme
+    #[serde(rename = "light")]
+    Light,
+    /// Images with a dark theme
+    #[serde(rename = "dark")]
+    Dark,
+    /// Images without a theme
+    #[serde(rename = "none")]
+    None,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorMatch {
+    /// Images must have at least one of the colors
+    #[serde(rename = "any")]
+    Any,
+    /// Images must have all of the colors
+    #[serde(rename = "all")]
+    All,
+}
+
+impl ThemeFilter {
+    pub fn matches(&self, theme: Option<ColorTheme>) -> bool {
+        match self {
+            ThemeFilter::Light => theme == Some(ColorTheme::Light),
+            ThemeFilter::Dark => theme == Some(ColorTheme::Dark),
+            ThemeFilter::None => theme.is_none(),
+        }
+    }
+}
+
+impl ConfigurationFilters {
+    /// Fill every unset filter with the value from `fallback`
+    pub fn or(self, fallback: ConfigurationFilters) -> ConfigurationFilters {
+        ConfigurationFilters {
+            active_directories: self.active_directories.or(fallback.active_directories),
+            scores: self.scores.or(fallback.scores),
+            width_range: self.width_range.or(fallback.width_range),
+            height_range: self.height_range.or(fallback.height_range),
+            tags: self.tags.or(fallback.tags),
+            any_tags: self.any_tags.or(fallback.any_tags),
+            excluded_tags: self.excluded_tags.or(fallback.excluded_tags),
+            theme: self.theme.or(fallback.theme),
+            colors: self.colors.or(fallback.colors),
+            color_match: self.color_match.or(fallback.color_match),
+            aspect: self.aspect.or(fallback.aspect),
+            aspect_range: self.aspect_range.or(fallback.aspect_range),
+            luminance_range: self.luminance_range.or(fallback.luminance_range),
+            orientation: self.orientation.or(fallback.orientation),
+            min_pixels: self.min_pixels.or(fallback.min_pixels),
+            query: self.query.or(fallback.query),
+        }
+    }
 }
 
 impl Configuration {
@@ -52,13 +226,71 @@ impl Configuration {
             scores: None,
             width_range: Some(RangeInclusive::new(0, 10_000)),
             height_range: Some(RangeInclusive::new(0, 10_000)),
+            ..Default::default()
         };
 
         Configuration {
             root_images_dir,
             metadata_path,
+            default_library: None,
             filters,
+            theme_detection: ThemeDetection::default(),
+            libraries: BTreeMap::new(),
+            presets: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            monitors: vec![],
+            top_level_library: None,
+        }
+    }
+
+    /// Names of all libraries, starting with the default one
+    pub fn library_names(&self) -> Vec<&str> {
+        let mut names = vec![DEFAULT_LIBRARY];
+        names.extend(
+            self.libraries
+                .keys()
+                .map(String::as_str)
+                .filter(|name| *name != DEFAULT_LIBRARY),
+        );
+        names
+    }
+
+    pub fn get_library(&self, name: &str) -> Result<Library> {
+        if let Some(library) = self.libraries.get(name) {
+            return Ok(library.clone());
+        }
+
+        if name == DEFAULT_LIBRARY {
+            return Ok(self.top_level_library());
         }
+
+        bail!(
+            "unknown library `{}`, expected one of: {}",
+            name,
+            self.library_names().join(", ")
+        )
+    }
+
+    /// Make `root_images_dir` and `metadata_path` point to the given library, or to the
+    /// `default_library` when no name is given
+    pub fn use_library(&mut self, name: Option<&str>) -> Result<()> {
+        let Some(name) = name.or(self.default_library.as_deref()) else {
+            return Ok(());
+        };
+
+        info!("using library: {name}");
+        let library = self.get_library(name)?;
+        self.top_level_library = Some(self.top_level_library());
+        self.root_images_dir = library.root_images_dir;
+        self.metadata_path = library.metadata_path;
+        Ok(())
+    }
+
+    fn top_level_library(&self) -> Library {
+        self.top_level_library.clone().unwrap_or_else(|| Library {
+            root_images_dir: self.root_images_dir.clone(),
+            metadata_path: self.metadata_path.clone(),
+        })
     }
 
     pub fn to_toml_str(&self) -> Result<String> {
@@ -67,3 +299,45 @@ impl Configuration {
         Ok(toml)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn theme_classification_thresholds_are_exclusive() {
+        let theme_detection = ThemeDetection::default();
+
+        assert_eq!(theme_detection.classify(0.44), Some(ColorTheme::Dark));
+        assert_eq!(theme_detection.classify(0.45), None);
+        assert_eq!(theme_detection.classify(0.55), None);
+        assert_eq!(theme_detection.classify(0.56), Some(ColorTheme::Light));
+    }
+
+    #[test]
+    fn default_library_is_top_level_paths_after_switching_library() {
+        let mut configuration = Configuration::create_default();
+        configuration.root_images_dir = PathBuf::from("/walls");
+        configuration.metadata_path = PathBuf::from("/walls/metadatas.json");
+        configuration.libraries.insert(
+            "shots".to_string(),
+            Library {
+                root_images_dir: PathBuf::from("/shots"),
+                metadata_path: PathBuf::from("/shots/metadatas.json"),
+            },
+        );
+
+        configuration.use_library(Some("shots")).unwrap();
+        assert_eq!(configuration.root_images_dir, PathBuf::from("/shots"));
+
+        let default = configuration.get_library(DEFAULT_LIBRARY).unwrap();
+        assert_eq!(default.root_images_dir, PathBuf::from("/walls"));
+        assert_eq!(
+            default.metadata_path,
+            PathBuf::from("/walls/metadatas.json")
+        );
+
+        configuration.use_library(Some(DEFAULT_LIBRARY)).unwrap();
+        assert_eq!(configuration.root_images_dir, PathBuf::from("/walls"));
+    }
+}
@@ -1,10 +1,11 @@
 use anyhow::{Context, Result};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::utils;
+use crate::utils::cache::FileCache;
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum ColorTheme {
     #[serde(rename = "light")]
     Light,
@@ -12,7 +13,7 @@ pub enum ColorTheme {
     Dark,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
 pub enum Color {
     #[serde(rename = "red")]
     Red,
@@ -21,6 +22,7 @@ pub enum Color {
     #[serde(rename = "blue")]
     Blue,
     #[serde(rename = "darkgray")]
+    #[value(name = "darkgray")]
     DarkGray,
     #[serde(rename = "black")]
     Black,
@@ -28,6 +30,113 @@ pub enum Color {
     White,
     #[serde(rename = "orange")]
     Orange,
+    #[serde(rename = "yellow")]
+    Yellow,
+    #[serde(rename = "cyan")]
+    Cyan,
+    #[serde(rename = "purple")]
+    Purple,
+    #[serde(rename = "pink")]
+    Pink,
+    #[serde(rename = "brown")]
+    Brown,
+    #[serde(rename = "gray")]
+    Gray,
+}
+
+/// Share of the image covered by a color
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PaletteColor {
+    pub color: Color,
+    /// Between 0 and 1
+    pub weight: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Orientation {
+    /// Wider than tall
+    #[serde(rename = "landscape")]
+    Landscape,
+    /// Taller than wide
+    #[serde(rename = "portrait")]
+    Portrait,
+    /// As wide as tall
+    #[serde(rename = "square")]
+    Square,
+}
+
+/// Perceived luminance of the pixels of an image, each between 0 (black) and 1 (white)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct LuminanceStats {
+    pub mean: f32,
+    pub median: f32,
+    /// Share of pixels darker than the configured dark pixel threshold
+    pub dark_ratio: f32,
+}
+
+impl Color {
+    /// Closest named color of an RGB color
+    pub fn from_rgb(rgb: [u8; 3]) -> Color {
+        let [r, g, b] = rgb.map(|c| c as f32 / 255.0);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let lightness = (max + min) / 2.0;
+        let chroma = max - min;
+        let saturation = match chroma {
+            0.0 => 0.0,
+            _ => chroma / (1.0 - (2.0 * lightness - 1.0).abs()),
+        };
+
+        if lightness < 0.1 {
+            return Color::Black;
+        }
+        if lightness > 0.92 {
+            return Color::White;
+        }
+        if saturation < 0.15 || chroma < 0.08 {
+            return match lightness {
+                l if l < 0.4 => Color::DarkGray,
+                l if l < 0.8 => Color::Gray,
+                _ => Color::White,
+            };
+        }
+
+        let hue = match max {
+            m if m == r => 60.0 * ((g - b) / chroma).rem_euclid(6.0),
+            m if m == g => 60.0 * ((b - r) / chroma + 2.0),
+            _ => 60.0 * ((r - g) / chroma + 4.0),
+        };
+
+        match hue {
+            h if !(15.0..345.0).contains(&h) => match lightness {
+                l if l > 0.7 => Color::Pink,
+                _ => Color::Red,
+            },
+            h if h < 45.0 => match lightness {
+                l if l < 0.35 => Color::Brown,
+                _ => Color::Orange,
+            },
+            h if h < 70.0 => match lightness {
+                l if l < 0.3 => Color::Brown,
+                _ => Color::Yellow,
+            },
+            h if h < 165.0 => Color::Green,
+            h if h < 195.0 => Color::Cyan,
+            h if h < 255.0 => Color::Blue,
+            h if h < 290.0 => Color::Purple,
+            _ => Color::Pink,
+        }
+    }
+}
+
+impl Orientation {
+    pub fn from_dims(width: u32, height: u32) -> Orientation {
+        match width.cmp(&height) {
+            std::cmp::Ordering::Greater => Orientation::Landscape,
+            std::cmp::Ordering::Less => Orientation::Portrait,
+            std::cmp::Ordering::Equal => Orientation::Square,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -42,19 +151,33 @@ pub struct ImageMeta {
     pub scores: Vec<ImageScore>,
     pub tags: Vec<String>,
     pub theme: Option<ColorTheme>,
+    /// Dominant colors, most prominent first
     pub colors: Vec<Color>,
+    #[serde(default)]
+    pub palette: Vec<PaletteColor>,
+    #[serde(default)]
+    pub luminance: Option<LuminanceStats>,
+    /// Perceptual hash (dHash) as 16 hexadecimal digits
+    #[serde(default)]
+    pub phash: Option<String>,
+}
+
+impl AsRef<ImageMeta> for ImageMeta {
+    fn as_ref(&self) -> &ImageMeta {
+        self
+    }
 }
 
 impl ImageMeta {
-    pub fn create_from_image(image: &PathBuf) -> Result<ImageMeta> {
-        let id = utils::common::compute_blake3_hash(image)?;
+    pub fn create_from_image(image: &Path, cache: &mut FileCache) -> Result<ImageMeta> {
+        let id = cache.get_hash(image)?;
         let filename = image
             .file_name()
             .context("image file should have a filename")?
             .to_string_lossy()
             .into_owned();
 
-        let dimensions = utils::common::get_image_dims(image)?;
+        let dimensions = cache.get_dims(image)?;
 
         let meta = ImageMeta {
             id,
@@ -67,10 +190,53 @@ impl ImageMeta {
             tags: vec![],
             theme: None,
             colors: vec![],
+            palette: vec![],
+            luminance: None,
+            phash: None,
         };
 
         Ok(meta)
     }
+
+    pub fn aspect_ratio(&self) -> f64 {
+        self.width as f64 / self.height.max(1) as f64
+    }
+
+    pub fn pixel_count(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        Orientation::from_dims(self.width, self.height)
+    }
+
+    pub fn perceptual_hash(&self) -> Option<u64> {
+        self.phash
+            .as_deref()
+            .and_then(|phash| u64::from_str_radix(phash, 16).ok())
+    }
+}
+
+#[cfg(test)]
+impl ImageMeta {
+    /// Metadata of an image that does not need to exist on disk
+    pub fn for_tests(path: &str, width: u32, height: u32) -> ImageMeta {
+        ImageMeta {
+            id: path.to_string(),
+            path: PathBuf::from(path),
+            title: path.to_string(),
+            description: String::new(),
+            width,
+            height,
+            scores: vec![],
+            tags: vec![],
+            theme: None,
+            colors: vec![],
+            palette: vec![],
+            luminance: None,
+            phash: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -78,3 +244,31 @@ pub struct ImageScore {
     pub name: String,
     pub value: u8,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_from_rgb() {
+        let cases = [
+            ([0, 0, 0], Color::Black),
+            ([255, 255, 255], Color::White),
+            ([128, 128, 128], Color::Gray),
+            ([60, 60, 60], Color::DarkGray),
+            ([220, 20, 20], Color::Red),
+            ([255, 182, 193], Color::Pink),
+            ([255, 140, 0], Color::Orange),
+            ([139, 69, 19], Color::Brown),
+            ([230, 220, 30], Color::Yellow),
+            ([30, 180, 40], Color::Green),
+            ([20, 200, 200], Color::Cyan),
+            ([20, 40, 200], Color::Blue),
+            ([130, 30, 200], Color::Purple),
+        ];
+
+        for (rgb, color) in cases {
+            assert_eq!(Color::from_rgb(rgb), color, "{rgb:?}");
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Collection of images with its own metadata file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Library {
+    #[serde(rename = "root_path")]
+    pub root_images_dir: PathBuf,
+
+    #[serde(rename = "meta_path")]
+    pub metadata_path: PathBuf,
+}
@@ -1,7 +1,18 @@
+pub mod aspect_filter;
 pub mod configuration;
 pub mod image_meta;
+pub mod library;
+pub mod monitor;
+pub mod query;
 pub mod score_filter;
+pub mod sort_key;
 
+pub use self::aspect_filter::AspectFilter;
 pub use self::configuration::Configuration;
+pub use self::configuration::ConfigurationFilters;
 pub use self::image_meta::ImageMeta;
+pub use self::library::Library;
+pub use self::monitor::Monitor;
+pub use self::query::Query;
 pub use self::score_filter::ScoreFilter;
+pub use self::sort_key::SortKey;
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+use super::{image_meta::Orientation, ConfigurationFilters};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Monitor {
+    /// Name of the output, e.g. `DP-1`
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Default is deduced from width and height
+    pub orientation: Option<Orientation>,
+    /// Extra filters applied on top of the usual selectors
+    #[serde(default)]
+    pub filters: ConfigurationFilters,
+}
+
+impl Monitor {
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+            .unwrap_or(Orientation::from_dims(self.width, self.height))
+    }
+
+    /// Width and height of the monitor once rotated to its orientation
+    fn rotated_dims(&self) -> (u32, u32) {
+        match (
+            self.orientation(),
+            Orientation::from_dims(self.width, self.height),
+        ) {
+            (Orientation::Portrait, Orientation::Landscape)
+            | (Orientation::Landscape, Orientation::Portrait) => (self.height, self.width),
+            _ => (self.width, self.height),
+        }
+    }
+
+    /// Filters selecting images suitable for this monitor: at least as big as the monitor and
+    /// with the same orientation, unless its extra filters say otherwise
+    pub fn to_filters(&self) -> ConfigurationFilters {
+        let (width, height) = self.rotated_dims();
+        let mut filters = self.filters.clone();
+        filters.width_range = filters
+            .width_range
+            .or(Some(RangeInclusive::new(width as usize, usize::MAX)));
+        filters.height_range = filters
+            .height_range
+            .or(Some(RangeInclusive::new(height as usize, usize::MAX)));
+        filters.orientation = filters.orientation.or(Some(self.orientation()));
+        filters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(orientation: Option<Orientation>) -> Monitor {
+        Monitor {
+            name: "DP-1".to_string(),
+            width: 1920,
+            height: 1080,
+            orientation,
+            filters: ConfigurationFilters::default(),
+        }
+    }
+
+    #[test]
+    fn native_orientation_keeps_dims() {
+        let filters = monitor(None).to_filters();
+
+        assert_eq!(filters.width_range, Some(1920..=usize::MAX));
+        assert_eq!(filters.height_range, Some(1080..=usize::MAX));
+        assert_eq!(filters.orientation, Some(Orientation::Landscape));
+    }
+
+    #[test]
+    fn rotated_orientation_swaps_dims() {
+        let filters = monitor(Some(Orientation::Portrait)).to_filters();
+
+        assert_eq!(filters.width_range, Some(1080..=usize::MAX));
+        assert_eq!(filters.height_range, Some(1920..=usize::MAX));
+        assert_eq!(filters.orientation, Some(Orientation::Portrait));
+    }
+}
@@ -0,0 +1,528 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::RangeInclusive, str::FromStr};
+
+use super::{configuration::ThemeFilter, image_meta::Color, ImageMeta, ScoreFilter};
+use crate::utils;
+
+/// Boolean expression used to select images, e.g.:
+/// `(score.favorite >= 3 or tag:pinned) and not tag:nsfw and width >= 1920`
+///
+/// Supported terms:
+/// - `tag:NAME`, `theme:light|dark|none`, `color:NAME`
+/// - `width OP N`, `height OP N`, `score.NAME OP N` where OP is one of `=`, `!=`, `<`, `<=`, `>`, `>=`
+/// - `score.NAME@ OP N` to also match images that have no value for this score
+/// - `= N..M` to compare against a range
+///
+/// Keywords and field names are case-insensitive, tag and score names are not.
+/// Terms are combined with `and`, `or`, `not` and parentheses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Query {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Score(ScoreFilter),
+    /// Score value outside of the range. Unlike `Not(Score)`, images without the score only
+    /// match if the filter allows unscored images
+    ScoreNotEq(ScoreFilter),
+    Width(RangeInclusive<usize>),
+    Height(RangeInclusive<usize>),
+    Tag(String),
+    Theme(ThemeFilter),
+    Color(Color),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    pub source: String,
+    pub position: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Colon,
+    Op(CmpOp),
+    Word(String),
+    Str(String),
+    End,
+}
+
+impl Query {
+    pub fn parse(source: &str) -> Result<Query, QueryError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            source,
+            tokens,
+            index: 0,
+        };
+
+        let expr = parser.parse_or()?;
+        if let (Token::End, _) = parser.peek() {
+            return Ok(Query {
+                source: source.to_string(),
+                expr,
+            });
+        }
+
+        Err(parser.error_here("expected `and`, `or` or end of query"))
+    }
+
+    pub fn matches(&self, meta: &ImageMeta) -> bool {
+        self.expr.matches(meta)
+    }
+}
+
+impl Expr {
+    fn matches(&self, meta: &ImageMeta) -> bool {
+        match self {
+            Expr::And(left, right) => left.matches(meta) && right.matches(meta),
+            Expr::Or(left, right) => left.matches(meta) || right.matches(meta),
+            Expr::Not(inner) => !inner.matches(meta),
+            Expr::Score(score_filter) => utils::common::image_score_matches(meta, score_filter),
+            Expr::ScoreNotEq(score_filter) => {
+                match meta.scores.iter().find(|s| s.name == score_filter.name) {
+                    Some(score) => !score_filter.range.contains(&usize::from(score.value)),
+                    None => score_filter.allow_unscored,
+                }
+            }
+            Expr::Width(range) => range.contains(&(meta.width as usize)),
+            Expr::Height(range) => range.contains(&(meta.height as usize)),
+            Expr::Tag(tag) => meta.tags.contains(tag),
+            Expr::Theme(theme) => theme.matches(meta.theme),
+            Expr::Color(color) => meta.colors.contains(color),
+        }
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &(Token, usize) {
+        &self.tokens[self.index.min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> (Token, usize) {
+        let token = self.peek().clone();
+        self.index += 1;
+        token
+    }
+
+    fn error_at(&self, position: usize, message: impl Into<String>) -> QueryError {
+        QueryError {
+            source: self.source.to_string(),
+            position,
+            message: message.into(),
+        }
+    }
+
+    fn error_here(&self, message: &str) -> QueryError {
+        let (token, position) = self.peek();
+        match token {
+            Token::End => self.error_at(*position, format!("{message}, got end of query")),
+            _ => self.error_at(*position, message),
+        }
+    }
+
+    fn next_is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), (Token::Word(word), _) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.parse_and()?;
+        while self.next_is_keyword("or") {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.parse_unary()?;
+        while self.next_is_keyword("and") {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, QueryError> {
+        if self.next_is_keyword("not") {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, QueryError> {
+        match self.next() {
+            (Token::LParen, position) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    (Token::RParen, _) => Ok(expr),
+                    _ => {
+                        self.index -= 1;
+                        Err(self.error_here(&format!(
+                            "expected `)` to close `(` at position {position}"
+                        )))
+                    }
+                }
+            }
+            (Token::Word(field), position) => self.parse_term(&field, position),
+            _ => {
+                self.index -= 1;
+                Err(self.error_here("expected a term, `not` or `(`"))
+            }
+        }
+    }
+
+    fn parse_term(&mut self, field: &str, position: usize) -> Result<Expr, QueryError> {
+        match self.next() {
+            (Token::Colon, _) => {
+                let (value, value_position) = match self.next() {
+                    (Token::Word(value) | Token::Str(value), value_position) => {
+                        (value, value_position)
+                    }
+                    _ => {
+                        self.index -= 1;
+                        return Err(self.error_here(&format!("expected a value after `{field}:`")));
+                    }
+                };
+
+                match field.to_lowercase().as_str() {
+                    "tag" => Ok(Expr::Tag(value)),
+                    "theme" => ThemeFilter::from_str(&value, true)
+                        .map(Expr::Theme)
+                        .map_err(|_| {
+                            self.error_at(value_position, format!("unknown theme: `{value}`"))
+                        }),
+                    "color" => Color::from_str(&value, true).map(Expr::Color).map_err(|_| {
+                        self.error_at(value_position, format!("unknown color: `{value}`"))
+                    }),
+                    _ => Err(self.error_at(
+                        position,
+                        format!("unknown field `{field}`, expected one of: tag, theme, color"),
+                    )),
+                }
+            }
+            (Token::Op(op), _) => {
+                let (value, value_position) = match self.next() {
+                    (Token::Word(value), value_position) => (value, value_position),
+                    _ => {
+                        self.index -= 1;
+                        return Err(self.error_here("expected a number or a range"));
+                    }
+                };
+
+                let range =
+                    parse_comparison(op, &value).map_err(|e| self.error_at(value_position, e))?;
+                let expr = match field.to_lowercase().as_str() {
+                    "width" => Expr::Width(range),
+                    "height" => Expr::Height(range),
+                    _ => match strip_prefix_ignore_case(field, "score.") {
+                        Some(name) if !name.is_empty() => {
+                            let (name, allow_unscored) = match name.strip_suffix('@') {
+                                Some(name) => (name, true),
+                                None => (name, false),
+                            };
+
+                            let score_filter = ScoreFilter {
+                                name: name.to_string(),
+                                range,
+                                allow_unscored,
+                            };
+                            return Ok(match op {
+                                CmpOp::NotEq => Expr::ScoreNotEq(score_filter),
+                                _ => Expr::Score(score_filter),
+                            });
+                        }
+                        _ => {
+                            return Err(self.error_at(
+                                position,
+                                format!(
+                                    "unknown field `{field}`, expected one of: width, height, score.NAME"
+                                ),
+                            ))
+                        }
+                    },
+                };
+
+                match op {
+                    CmpOp::NotEq => Ok(Expr::Not(Box::new(expr))),
+                    _ => Ok(expr),
+                }
+            }
+            _ => {
+                self.index -= 1;
+                Err(self.error_here(&format!(
+                    "expected `:` or a comparison operator after `{field}`"
+                )))
+            }
+        }
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    match value.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&value[prefix.len()..]),
+        _ => None,
+    }
+}
+
+fn parse_comparison(op: CmpOp, value: &str) -> Result<RangeInclusive<usize>, String> {
+    if matches!(op, CmpOp::Eq | CmpOp::NotEq) {
+        return utils::common::parse_range(value).map_err(|e| e.to_string());
+    }
+
+    let num = value
+        .parse::<usize>()
+        .map_err(|_| format!("expected a number but got: `{value}`"))?;
+
+    // Empty ranges (e.g. `< 0`) never match
+    #[allow(clippy::reversed_empty_ranges)]
+    let range = match op {
+        CmpOp::Lt => num.checked_sub(1).map_or(1..=0, |end| 0..=end),
+        CmpOp::LtEq => 0..=num,
+        CmpOp::Gt => num.checked_add(1).map_or(1..=0, |start| start..=usize::MAX),
+        CmpOp::GtEq => num..=usize::MAX,
+        CmpOp::Eq | CmpOp::NotEq => unreachable!("equality is handled above"),
+    };
+
+    Ok(range)
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, QueryError> {
+    let error = |position: usize, message: String| QueryError {
+        source: source.to_string(),
+        position,
+        message,
+    };
+
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((position, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ':' => Token::Colon,
+            '=' => {
+                chars.next_if(|(_, c)| *c == '=');
+                Token::Op(CmpOp::Eq)
+            }
+            '!' => match chars.next_if(|(_, c)| *c == '=') {
+                Some(_) => Token::Op(CmpOp::NotEq),
+                None => return Err(error(position, "expected `!=`".to_string())),
+            },
+            '<' => match chars.next_if(|(_, c)| *c == '=') {
+                Some(_) => Token::Op(CmpOp::LtEq),
+                None => Token::Op(CmpOp::Lt),
+            },
+            '>' => match chars.next_if(|(_, c)| *c == '=') {
+                Some(_) => Token::Op(CmpOp::GtEq),
+                None => Token::Op(CmpOp::Gt),
+            },
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((_, other)) => value.push(other),
+                        None => return Err(error(position, "unterminated string".to_string())),
+                    }
+                }
+                Token::Str(value)
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| !c.is_whitespace() && !"():=!<>\"'".contains(*c))
+                {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+        };
+
+        tokens.push((token, position));
+    }
+
+    tokens.push((Token::End, source.len()));
+    Ok(tokens)
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let column = self.source[..self.position].chars().count();
+        writeln!(
+            f,
+            "invalid query at position {}: {}",
+            self.position, self.message
+        )?;
+        writeln!(f, "  {}", self.source)?;
+        write!(f, "  {}^", " ".repeat(column))
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        Query::parse(source)
+    }
+}
+
+impl TryFrom<String> for Query {
+    type Error = QueryError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        Query::parse(&source)
+    }
+}
+
+impl From<Query> for String {
+    fn from(query: Query) -> Self {
+        query.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::image_meta::ImageScore;
+
+    fn tagged(tags: &[&str]) -> ImageMeta {
+        ImageMeta {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..ImageMeta::for_tests("a.png", 1920, 1080)
+        }
+    }
+
+    fn scored(name: &str, value: u8) -> ImageMeta {
+        ImageMeta {
+            scores: vec![ImageScore {
+                name: name.to_string(),
+                value,
+            }],
+            ..ImageMeta::for_tests("a.png", 1920, 1080)
+        }
+    }
+
+    fn matches(query: &str, meta: &ImageMeta) -> bool {
+        Query::parse(query).unwrap().matches(meta)
+    }
+
+    fn error_position(query: &str) -> usize {
+        Query::parse(query).unwrap_err().position
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let query = "tag:a or tag:b and tag:c";
+        assert!(matches(query, &tagged(&["a"])));
+        assert!(!matches(query, &tagged(&["b"])));
+        assert!(matches(query, &tagged(&["b", "c"])));
+
+        let query = "(tag:a or tag:b) and tag:c";
+        assert!(!matches(query, &tagged(&["a"])));
+        assert!(matches(query, &tagged(&["a", "c"])));
+    }
+
+    #[test]
+    fn not_applies_to_the_next_term_only() {
+        let query = "not tag:a and tag:b";
+        assert!(matches(query, &tagged(&["b"])));
+        assert!(!matches(query, &tagged(&["a", "b"])));
+        assert!(!matches(query, &tagged(&[])));
+
+        assert!(matches("not not tag:a", &tagged(&["a"])));
+        assert!(matches("NOT (tag:a or tag:b)", &tagged(&["c"])));
+    }
+
+    #[test]
+    fn not_equal_negates_comparison() {
+        let meta = ImageMeta::for_tests("a.png", 1920, 1080);
+        assert!(!matches("width != 1920", &meta));
+        assert!(matches("width != 1280", &meta));
+        assert!(!matches("height != 1000..1100", &meta));
+        assert!(matches("height = 1000..1100", &meta));
+    }
+
+    #[test]
+    fn at_suffix_allows_unscored_images() {
+        let unscored = tagged(&[]);
+        assert!(!matches("score.favorite >= 3", &unscored));
+        assert!(matches("score.favorite@ >= 3", &unscored));
+
+        assert!(matches("score.favorite@ >= 3", &scored("favorite", 4)));
+        assert!(!matches("score.favorite@ >= 3", &scored("favorite", 2)));
+    }
+
+    #[test]
+    fn not_equal_keeps_unscored_rule() {
+        let unscored = tagged(&[]);
+        assert!(!matches("score.favorite != 3", &unscored));
+        assert!(matches("score.favorite@ != 3", &unscored));
+
+        assert!(matches("score.favorite != 3", &scored("favorite", 4)));
+        assert!(!matches("score.favorite@ != 3..5", &scored("favorite", 4)));
+    }
+
+    #[test]
+    fn field_names_ignore_case_but_score_names_do_not() {
+        let meta = scored("favorite", 4);
+        assert!(matches("SCORE.favorite >= 3 and Width >= 1920", &meta));
+        assert!(!matches("score.Favorite >= 3", &meta));
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        assert_eq!(error_position("tag:a and"), 9);
+        assert_eq!(error_position("tag:a tag:b"), 6);
+        assert_eq!(error_position("(tag:a"), 6);
+        assert_eq!(error_position("width >= wide"), 9);
+        assert_eq!(error_position("size > 3"), 0);
+        assert_eq!(error_position("theme:sepia"), 6);
+        assert_eq!(error_position("tag:a ! tag:b"), 6);
+        assert_eq!(error_position("tag:'unterminated"), 4);
+    }
+
+    #[test]
+    fn error_at_end_of_query_says_so() {
+        let error = Query::parse("tag:a and").unwrap_err();
+        assert!(
+            error.message.ends_with("got end of query"),
+            "{}",
+            error.message
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+
+/// Attribute used to sort images
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortField {
+    Path,
+    Title,
+    Width,
+    Height,
+    Area,
+    AspectRatio,
+    Score(String),
+    ModifiedTime,
+    Size,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+impl SortField {
+    /// Whether sorting on this field requires reading file system metadata
+    pub fn needs_file_stat(&self) -> bool {
+        matches!(self, SortField::ModifiedTime | SortField::Size)
+    }
+}
+
+impl fmt::Display for SortField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortField::Path => write!(f, "path"),
+            SortField::Title => write!(f, "title"),
+            SortField::Width => write!(f, "width"),
+            SortField::Height => write!(f, "height"),
+            SortField::Area => write!(f, "area"),
+            SortField::AspectRatio => write!(f, "ratio"),
+            SortField::Score(name) => write!(f, "score.{name}"),
+            SortField::ModifiedTime => write!(f, "mtime"),
+            SortField::Size => write!(f, "size"),
+        }
+    }
+}
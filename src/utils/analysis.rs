@@ -0,0 +1,419 @@
+use anyhow::{Context, Result};
+use image::{imageops::FilterType, DynamicImage, RgbaImage};
+use log::{debug, warn};
+use rayon::prelude::*;
+use std::{collections::HashMap, path::Path};
+
+use super::progress::Progress;
+use crate::models::{
+    configuration::ThemeDetection,
+    image_meta::{Color, ColorTheme, LuminanceStats, PaletteColor},
+    ImageMeta,
+};
+
+/// Size of the thumbnail analyzed instead of the full image
+const THUMBNAIL_SIZE: u32 = 64;
+/// Number of clusters computed by k-means
+const CLUSTER_COUNT: usize = 6;
+const MAX_ITERATIONS: usize = 20;
+/// Colors covering less than this share of the image are ignored
+const MIN_COLOR_WEIGHT: f32 = 0.05;
+
+/// Properties computed from the pixels of an image
+#[derive(Debug, Clone)]
+pub struct ImageAnalysis {
+    pub palette: Vec<PaletteColor>,
+    pub luminance: Option<LuminanceStats>,
+    pub theme: Option<ColorTheme>,
+    pub phash: u64,
+}
+
+impl ImageAnalysis {
+    /// Store the analysis in the metadata. `colors` and `theme` may have been set by hand, so
+    /// they are only filled when empty unless `force` is set
+    pub fn apply_to(self, meta: &mut ImageMeta, force: bool) {
+        if force || meta.colors.is_empty() {
+            meta.colors = self.palette.iter().map(|p| p.color).collect();
+        }
+        if force || meta.theme.is_none() {
+            meta.theme = self.theme;
+        }
+        meta.palette = self.palette;
+        meta.luminance = self.luminance;
+        meta.phash = Some(format!("{:016x}", self.phash));
+    }
+}
+
+pub fn analyze_image(image: &Path, theme_detection: &ThemeDetection) -> Result<ImageAnalysis> {
+    let decoded = image::open(image)
+        .with_context(|| format!("failed to decode image: {}", image.display()))?;
+    let thumbnail = decoded
+        .resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle)
+        .to_rgba8();
+
+    let luminance = compute_luminance(&thumbnail, theme_detection.dark_pixel);
+    Ok(ImageAnalysis {
+        palette: extract_palette(&thumbnail),
+        theme: luminance.and_then(|stats| theme_detection.classify(stats.mean as f64)),
+        luminance,
+        phash: compute_dhash(&decoded),
+    })
+}
+
+/// Number of differing bits between two perceptual hashes
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Distance between two palettes, from 0 (same colors in the same proportions) to 1 (no color
+/// in common)
+pub fn palette_distance(a: &[PaletteColor], b: &[PaletteColor]) -> f32 {
+    let mut histogram: HashMap<Color, f32> = HashMap::new();
+    for entry in a.iter() {
+        *histogram.entry(entry.color).or_default() += entry.weight;
+    }
+    for entry in b.iter() {
+        *histogram.entry(entry.color).or_default() -= entry.weight;
+    }
+
+    // Weights of a palette add up to about 1, so the L1 distance is between 0 and 2
+    let distance: f32 = histogram.values().map(|weight| weight.abs()).sum();
+    (distance / 2.0).min(1.0)
+}
+
+/// Difference hash: one bit per pair of horizontally adjacent pixels of a 9x8 grayscale
+/// version of the image, set when the left pixel is brighter. Resizing and re-encoding barely
+/// change it
+fn compute_dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | bit as u64;
+        }
+    }
+    hash
+}
+
+/// Analyze images in parallel and update their metadata, see [`ImageAnalysis::apply_to`].
+/// Returns the number of failures
+pub fn analyze_image_metas(
+    metas: &mut [ImageMeta],
+    theme_detection: &ThemeDetection,
+    force: bool,
+    progress: Option<&Progress>,
+) -> usize {
+    metas
+        .par_iter_mut()
+        .map(|meta| {
+            let result = analyze_image(&meta.path, theme_detection);
+            if let Some(progress) = progress {
+                progress.tick(&meta.path);
+            }
+
+            match result {
+                Ok(analysis) => {
+                    debug!("analyzed {}: {:?}", meta.path.display(), analysis);
+                    analysis.apply_to(meta, force);
+                    0
+                }
+                Err(e) => {
+                    warn!("failed to analyze {}: {:#}", meta.path.display(), e);
+                    1
+                }
+            }
+        })
+        .sum()
+}
+
+/// Luminance statistics of the opaque pixels, using Rec. 601 luma
+fn compute_luminance(thumbnail: &RgbaImage, dark_pixel: f64) -> Option<LuminanceStats> {
+    let mut histogram = [0usize; 256];
+    for pixel in thumbnail.pixels().filter(|pixel| pixel[3] >= 128) {
+        let luma = 0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32;
+        histogram[luma.round() as usize] += 1;
+    }
+
+    let total: usize = histogram.iter().sum();
+    if total == 0 {
+        return None;
+    }
+
+    let weighted_sum: usize = histogram.iter().enumerate().map(|(l, n)| l * n).sum();
+    let mut seen = 0;
+    let median = histogram
+        .iter()
+        .position(|n| {
+            seen += n;
+            seen * 2 >= total
+        })
+        .unwrap_or(0);
+    let dark_count: usize = histogram
+        .iter()
+        .enumerate()
+        .filter(|(l, _)| (*l as f64 / 255.0) < dark_pixel)
+        .map(|(_, n)| n)
+        .sum();
+
+    let round = |value: f32| (value * 1000.0).round() / 1000.0;
+    Some(LuminanceStats {
+        mean: round(weighted_sum as f32 / total as f32 / 255.0),
+        median: round(median as f32 / 255.0),
+        dark_ratio: round(dark_count as f32 / total as f32),
+    })
+}
+
+/// Dominant colors using k-means over the opaque pixels, most prominent first
+fn extract_palette(thumbnail: &RgbaImage) -> Vec<PaletteColor> {
+    let pixels: Vec<[f32; 3]> = thumbnail
+        .pixels()
+        .filter(|pixel| pixel[3] >= 128)
+        .map(|pixel| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+        .collect();
+
+    if pixels.is_empty() {
+        return vec![];
+    }
+
+    let (centroids, counts) = kmeans(&pixels, CLUSTER_COUNT);
+
+    let mut weights: HashMap<Color, f32> = HashMap::new();
+    for (centroid, count) in centroids.iter().zip(counts.iter()) {
+        let color = Color::from_rgb(centroid.map(|c| c.round() as u8));
+        *weights.entry(color).or_default() += *count as f32 / pixels.len() as f32;
+    }
+
+    let mut palette: Vec<PaletteColor> = weights
+        .into_iter()
+        .filter(|(_, weight)| *weight >= MIN_COLOR_WEIGHT)
+        .map(|(color, weight)| PaletteColor {
+            color,
+            weight: (weight * 100.0).round() / 100.0,
+        })
+        .collect();
+
+    palette.sort_by(|a, b| b.weight.total_cmp(&a.weight));
+    palette
+}
+
+/// Cluster pixels, returning the centroid and the number of pixels of each cluster
+fn kmeans(pixels: &[[f32; 3]], cluster_count: usize) -> (Vec<[f32; 3]>, Vec<usize>) {
+    // Fixed seed so that analyzing the same image always gives the same palette
+    let mut rng = fastrand::Rng::with_seed(0);
+
+    // k-means++ initialization: favor centroids far from the ones already chosen
+    let mut centroids = vec![pixels[rng.usize(..pixels.len())]];
+    while centroids.len() < cluster_count {
+        let distances: Vec<f32> = pixels
+            .iter()
+            .map(|pixel| nearest_centroid(pixel, &centroids).1)
+            .collect();
+        let total: f32 = distances.iter().sum();
+        if total == 0.0 {
+            break;
+        }
+
+        let mut target = rng.f32() * total;
+        let index = distances
+            .iter()
+            .position(|distance| {
+                target -= distance;
+                target <= 0.0
+            })
+            .unwrap_or(pixels.len() - 1);
+        centroids.push(pixels[index]);
+    }
+
+    let mut assignments = vec![0; pixels.len()];
+    for iteration in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (pixel, assignment) in pixels.iter().zip(assignments.iter_mut()) {
+            let (nearest, _) = nearest_centroid(pixel, &centroids);
+            changed |= *assignment != nearest;
+            *assignment = nearest;
+        }
+
+        let mut sums = vec![[0.0; 3]; centroids.len()];
+        let mut counts = vec![0; centroids.len()];
+        for (pixel, assignment) in pixels.iter().zip(assignments.iter()) {
+            for channel in 0..3 {
+                sums[*assignment][channel] += pixel[channel];
+            }
+            counts[*assignment] += 1;
+        }
+
+        for ((centroid, sum), count) in centroids.iter_mut().zip(sums).zip(counts.iter()) {
+            if *count > 0 {
+                *centroid = sum.map(|s| s / *count as f32);
+            }
+        }
+
+        if !changed && iteration > 0 {
+            debug!("k-means converged after {} iterations", iteration);
+            return (centroids, counts);
+        }
+    }
+
+    let mut counts = vec![0; centroids.len()];
+    for assignment in assignments {
+        counts[assignment] += 1;
+    }
+    (centroids, counts)
+}
+
+/// Index of and squared distance to the nearest centroid
+fn nearest_centroid(pixel: &[f32; 3], centroids: &[[f32; 3]]) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|centroid| {
+            (0..3)
+                .map(|channel| (pixel[channel] - centroid[channel]).powi(2))
+                .sum::<f32>()
+        })
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    const BLUE: Rgba<u8> = Rgba([20, 40, 200, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+    #[test]
+    fn luminance_of_known_pixels() {
+        let mut thumbnail = RgbaImage::from_pixel(4, 1, Rgba([0, 0, 0, 255]));
+        thumbnail.put_pixel(1, 0, WHITE);
+        thumbnail.put_pixel(2, 0, WHITE);
+        // Transparent pixels are ignored
+        thumbnail.put_pixel(3, 0, Rgba([255, 255, 255, 0]));
+
+        let stats = compute_luminance(&thumbnail, 0.25).unwrap();
+
+        assert_eq!(stats.mean, 0.667);
+        assert_eq!(stats.median, 1.0);
+        assert_eq!(stats.dark_ratio, 0.333);
+    }
+
+    #[test]
+    fn luminance_of_solid_gray() {
+        let thumbnail = RgbaImage::from_pixel(8, 8, Rgba([128, 128, 128, 255]));
+
+        let stats = compute_luminance(&thumbnail, 0.25).unwrap();
+
+        assert_eq!(stats.mean, 0.502);
+        assert_eq!(stats.median, 0.502);
+        assert_eq!(stats.dark_ratio, 0.0);
+    }
+
+    #[test]
+    fn luminance_of_transparent_image_is_unknown() {
+        let thumbnail = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 0]));
+
+        assert_eq!(compute_luminance(&thumbnail, 0.25), None);
+    }
+
+    #[test]
+    fn kmeans_finds_separated_clusters() {
+        let mut pixels = vec![[10.0, 10.0, 10.0]; 30];
+        pixels.extend(vec![[240.0, 240.0, 240.0]; 10]);
+
+        let (centroids, counts) = kmeans(&pixels, 2);
+        let mut clusters: Vec<_> = centroids.into_iter().zip(counts).collect();
+        clusters.sort_by(|a, b| a.0[0].total_cmp(&b.0[0]));
+
+        assert_eq!(
+            clusters,
+            vec![([10.0, 10.0, 10.0], 30), ([240.0, 240.0, 240.0], 10)]
+        );
+    }
+
+    #[test]
+    fn kmeans_stops_at_distinct_colors() {
+        let pixels = vec![[50.0, 100.0, 150.0]; 20];
+
+        let (centroids, counts) = kmeans(&pixels, CLUSTER_COUNT);
+
+        assert_eq!(centroids, vec![[50.0, 100.0, 150.0]]);
+        assert_eq!(counts, vec![20]);
+    }
+
+    #[test]
+    fn palette_of_solid_image() {
+        let thumbnail = RgbaImage::from_pixel(8, 8, BLUE);
+
+        assert_eq!(
+            extract_palette(&thumbnail),
+            vec![PaletteColor {
+                color: Color::Blue,
+                weight: 1.0
+            }]
+        );
+    }
+
+    #[test]
+    fn palette_of_two_color_image() {
+        let thumbnail = RgbaImage::from_fn(8, 8, |x, _| if x < 6 { BLUE } else { WHITE });
+
+        assert_eq!(
+            extract_palette(&thumbnail),
+            vec![
+                PaletteColor {
+                    color: Color::Blue,
+                    weight: 0.75
+                },
+                PaletteColor {
+                    color: Color::White,
+                    weight: 0.25
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dhash_of_solid_image_is_zero() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(90, 80, BLUE));
+
+        assert_eq!(compute_dhash(&image), 0);
+    }
+
+    #[test]
+    fn dhash_sets_bits_where_left_pixel_is_brighter() {
+        let gradient = RgbaImage::from_fn(90, 80, |x, _| {
+            let value = 255 - (x * 2) as u8;
+            Rgba([value, value, value, 255])
+        });
+
+        assert_eq!(compute_dhash(&DynamicImage::ImageRgba8(gradient)), u64::MAX);
+    }
+
+    #[test]
+    fn apply_keeps_colors_and_theme_unless_forced() {
+        let analysis = ImageAnalysis {
+            palette: vec![PaletteColor {
+                color: Color::Blue,
+                weight: 1.0,
+            }],
+            luminance: None,
+            theme: Some(ColorTheme::Dark),
+            phash: 1,
+        };
+        let mut meta = ImageMeta::for_tests("a.png", 10, 10);
+        meta.colors = vec![Color::Red];
+        meta.theme = Some(ColorTheme::Light);
+
+        analysis.clone().apply_to(&mut meta, false);
+        assert_eq!(meta.colors, vec![Color::Red]);
+        assert_eq!(meta.theme, Some(ColorTheme::Light));
+        assert_eq!(meta.phash.as_deref(), Some("0000000000000001"));
+
+        analysis.apply_to(&mut meta, true);
+        assert_eq!(meta.colors, vec![Color::Blue]);
+        assert_eq!(meta.theme, Some(ColorTheme::Dark));
+    }
+}
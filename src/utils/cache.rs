@@ -0,0 +1,245 @@
+use anyhow::Result;
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use super::{common, progress::Progress, store};
+
+/// Cached information about an image file, valid as long as its size and mtime do not change
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub size: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+    pub hash: Option<String>,
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl CacheEntry {
+    fn is_same_file(&self, other: &CacheEntry) -> bool {
+        self.size == other.size
+            && self.mtime_secs == other.mtime_secs
+            && self.mtime_nanos == other.mtime_nanos
+    }
+}
+
+/// Persistent cache of file hashes and dimensions, stored in the config directory
+pub struct FileCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, CacheEntry>,
+    dirty: bool,
+}
+
+impl FileCache {
+    /// Load the cache from disk. If `rehash` is true, cached values are ignored and recomputed
+    pub fn load(rehash: bool) -> Result<FileCache> {
+        let path = common::get_config_dir()?.join("cache.json");
+        let entries = match fs::read_to_string(&path) {
+            Ok(_) if rehash => {
+                info!("rehash requested, ignoring cache file: {}", path.display());
+                HashMap::new()
+            }
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("ignoring invalid cache file {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => {
+                info!("no cache file found at: {}", path.display());
+                HashMap::new()
+            }
+        };
+
+        debug!("loaded {} cache entries", entries.len());
+        Ok(FileCache {
+            path,
+            entries,
+            dirty: false,
+        })
+    }
+
+    pub fn get_hash(&mut self, file: &Path) -> Result<String> {
+        let entry = self.get_entry(file)?;
+        if let Some(hash) = &entry.hash {
+            return Ok(hash.clone());
+        }
+
+        debug!("cache miss for hash of: {}", file.display());
+        let hash = common::compute_blake3_hash(file)?;
+        entry.hash = Some(hash.clone());
+        self.dirty = true;
+        Ok(hash)
+    }
+
+    pub fn get_dims(&mut self, file: &Path) -> Result<(u32, u32)> {
+        let entry = self.get_entry(file)?;
+        if let Some(dimensions) = entry.dimensions {
+            return Ok(dimensions);
+        }
+
+        debug!("cache miss for dimensions of: {}", file.display());
+        let dimensions = common::get_image_dims(file)?;
+        entry.dimensions = Some(dimensions);
+        self.dirty = true;
+        Ok(dimensions)
+    }
+
+    /// Compute missing hashes and dimensions of `files` in parallel, so that following `get_hash`
+    /// and `get_dims` calls are cache hits. Failures are logged and left for the getters to report
+    pub fn prefetch(
+        &mut self,
+        files: &[&Path],
+        with_hash: bool,
+        with_dims: bool,
+        progress: Option<&Progress>,
+    ) {
+        let entries: Vec<(PathBuf, CacheEntry)> = files
+            .par_iter()
+            .filter_map(|file| {
+                let entry = self.fetch_entry(file, with_hash, with_dims);
+                if let Some(progress) = progress {
+                    progress.tick(file);
+                }
+
+                Some((file.to_path_buf(), entry?))
+            })
+            .collect();
+
+        for (file, entry) in entries {
+            if self.entries.get(&file) != Some(&entry) {
+                self.entries.insert(file, entry);
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Write the cache back to disk if anything changed.
+    ///
+    /// Entries saved by other processes since the cache was loaded are kept, so that concurrent
+    /// kanumi processes do not undo each other's work. Entries of files that were deleted or
+    /// modified since they were cached are dropped
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let _lock = store::lock(&self.path)?;
+        let mut entries: HashMap<PathBuf, CacheEntry> = fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+        entries.extend(self.entries.clone());
+        let entries = prune_stale_entries(entries);
+
+        let data = serde_json::to_string(&entries)?;
+        store::write_atomic(&self.path, data.as_bytes())?;
+        info!("saved {} cache entries", entries.len());
+        Ok(())
+    }
+
+    /// Get an up to date copy of the entry of a file, computing what is missing
+    fn fetch_entry(&self, file: &Path, with_hash: bool, with_dims: bool) -> Option<CacheEntry> {
+        let stat = stat_file(file)
+            .inspect_err(|e| warn!("failed to stat {}: {}", file.display(), e))
+            .ok()?;
+
+        let mut entry = match self.entries.get(file) {
+            Some(entry) if entry.is_same_file(&stat) => entry.clone(),
+            _ => stat,
+        };
+
+        if with_hash && entry.hash.is_none() {
+            entry.hash = common::compute_blake3_hash(file)
+                .inspect_err(|e| warn!("failed to hash {}: {}", file.display(), e))
+                .ok();
+        }
+
+        if with_dims && entry.dimensions.is_none() {
+            entry.dimensions = common::get_image_dims(file)
+                .inspect_err(|e| warn!("failed to probe {}: {}", file.display(), e))
+                .ok();
+        }
+
+        Some(entry)
+    }
+
+    /// Get the entry of a file, resetting it if the file changed since it was cached
+    fn get_entry(&mut self, file: &Path) -> Result<&mut CacheEntry> {
+        let stat = stat_file(file)?;
+        let is_fresh = self
+            .entries
+            .get(file)
+            .is_some_and(|entry| entry.is_same_file(&stat));
+
+        if !is_fresh {
+            self.entries.insert(file.to_path_buf(), stat);
+            self.dirty = true;
+        }
+
+        Ok(self
+            .entries
+            .get_mut(file)
+            .expect("entry should have just been checked or inserted"))
+    }
+}
+
+/// Keep entries that still describe their file
+fn prune_stale_entries(entries: HashMap<PathBuf, CacheEntry>) -> HashMap<PathBuf, CacheEntry> {
+    let count = entries.len();
+    let entries: HashMap<PathBuf, CacheEntry> = entries
+        .into_par_iter()
+        .filter(|(file, entry)| stat_file(file).is_ok_and(|stat| entry.is_same_file(&stat)))
+        .collect();
+
+    debug!("pruned {} stale cache entries", count - entries.len());
+    entries
+}
+
+fn stat_file(file: &Path) -> Result<CacheEntry> {
+    let metadata = fs::metadata(file)?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+
+    Ok(CacheEntry {
+        size: metadata.len(),
+        mtime_secs: mtime.as_secs(),
+        mtime_nanos: mtime.subsec_nanos(),
+        hash: None,
+        dimensions: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_entries_are_pruned() {
+        let dir = std::env::temp_dir().join(format!("kanumi-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let fresh = dir.join("fresh.png");
+        let modified = dir.join("modified.png");
+        fs::write(&fresh, "fresh").unwrap();
+        fs::write(&modified, "before").unwrap();
+
+        let mut entries = HashMap::new();
+        for file in [&fresh, &modified] {
+            entries.insert(file.clone(), stat_file(file).unwrap());
+        }
+        entries.insert(dir.join("deleted.png"), stat_file(&fresh).unwrap());
+        fs::write(&modified, "after, with another size").unwrap();
+
+        let entries = prune_stale_entries(entries);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec![&fresh]);
+    }
+}
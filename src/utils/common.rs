@@ -1,16 +1,18 @@
 use anyhow::{anyhow, bail, Context, Result};
+use jwalk::{DirEntry, WalkDir};
 use log::{debug, info, warn};
+use serde_json::{Map, Value};
 use std::{
     env,
-    fs::{self, File},
-    io::Read,
+    fmt::Display,
+    fs,
     ops::RangeInclusive,
     path::{Path, PathBuf},
 };
-use walkdir::{DirEntry, WalkDir};
 use xdg::BaseDirectories;
 
-use crate::models::{Configuration, ImageMeta, ScoreFilter};
+use super::cache::FileCache;
+use crate::models::{sort_key::SortField, Configuration, ImageMeta, ScoreFilter, SortKey};
 
 pub const APP_NAME: &str = "kanumi";
 pub const CONFIG_VAR: &str = "KANUMI_CONFIG";
@@ -99,6 +101,35 @@ pub fn parse_score_filters(input: &str) -> Result<ScoreFilter> {
     Ok(score_filter)
 }
 
+pub fn parse_sort_key(input: &str) -> Result<SortKey> {
+    let (field, descending) = match input.rsplit_once(':') {
+        Some((field, "asc")) => (field, false),
+        Some((field, "desc")) => (field, true),
+        Some((_, order)) => bail!("invalid sort order `{}`, expected asc or desc", order),
+        None => (input, false),
+    };
+
+    let field = match field {
+        "path" => SortField::Path,
+        "title" => SortField::Title,
+        "width" => SortField::Width,
+        "height" => SortField::Height,
+        "area" => SortField::Area,
+        "ratio" => SortField::AspectRatio,
+        "mtime" => SortField::ModifiedTime,
+        "size" => SortField::Size,
+        _ => match field.strip_prefix("score.") {
+            Some(name) if !name.is_empty() => SortField::Score(name.to_string()),
+            _ => bail!(
+                "unknown sort key `{}`, expected one of: path, title, width, height, area, ratio, mtime, size, score.NAME",
+                field
+            ),
+        },
+    };
+
+    Ok(SortKey { field, descending })
+}
+
 pub fn parse_range(input: &str) -> Result<RangeInclusive<usize>> {
     if let Ok(num) = input.parse::<usize>() {
         return Ok(num..=num);
@@ -138,27 +169,105 @@ pub fn parse_range(input: &str) -> Result<RangeInclusive<usize>> {
     }
 }
 
-pub fn get_image_dims(image: &PathBuf) -> Result<(u32, u32)> {
+pub fn parse_float_range(input: &str) -> Result<RangeInclusive<f64>> {
+    if let Ok(num) = input.parse::<f64>() {
+        if !num.is_finite() {
+            bail!("number should be finite: `{}`", input);
+        }
+        return Ok(num..=num);
+    }
+
+    let Some((start, end)) = input.split_once("..") else {
+        bail!("expected number N or range (N..O) but got: `{}`", input);
+    };
+
+    let parse_bound = |part: &str, default: f64| -> Result<f64> {
+        match part.is_empty() {
+            true => Ok(default),
+            false => part
+                .parse::<f64>()
+                .with_context(|| format!("failed to parse number: `{}`", part))
+                .and_then(|num| match num.is_finite() {
+                    true => Ok(num),
+                    false => Err(anyhow!("number should be finite: `{}`", part)),
+                }),
+        }
+    };
+
+    if start.is_empty() && end.is_empty() {
+        bail!("range should have at least one boundary");
+    }
+
+    let (start, end) = (parse_bound(start, 0.0)?, parse_bound(end, f64::MAX)?);
+    if start > end {
+        bail!("start should be <= end: {} > {}", start, end);
+    }
+
+    Ok(start..=end)
+}
+
+/// Parse a pixel count, with an optional `k` (thousand) or `M` (million) suffix, e.g. `2M`
+pub fn parse_pixel_count(input: &str) -> Result<u64> {
+    let (number, multiplier) = match input.strip_suffix(['k', 'K']) {
+        Some(number) => (number, 1_000.0),
+        None => match input.strip_suffix(['m', 'M']) {
+            Some(number) => (number, 1_000_000.0),
+            None => (input, 1.0),
+        },
+    };
+
+    let number = number.parse::<f64>().with_context(|| {
+        format!(
+            "expected a pixel count like 2073600 or 2M but got: `{}`",
+            input
+        )
+    })?;
+    if !number.is_finite() {
+        bail!("pixel count should be finite: `{}`", input);
+    }
+    if number < 0.0 {
+        bail!("pixel count should be positive: `{}`", input);
+    }
+
+    let count = (number * multiplier).round();
+    if count > u64::MAX as f64 {
+        bail!("pixel count is too large: `{}`", input);
+    }
+
+    Ok(count as u64)
+}
+
+pub fn get_image_dims(image: &Path) -> Result<(u32, u32)> {
     Ok(image::image_dimensions(image)?)
 }
 
 pub fn image_matches_dims(
-    image: &PathBuf,
+    image: &Path,
+    cache: &mut FileCache,
     width_range: &Option<RangeInclusive<usize>>,
     height_range: &Option<RangeInclusive<usize>>,
 ) -> bool {
     debug!("checking dimensions for: {}", image.display());
-    let dimensions = image::image_dimensions(image);
-    if dimensions.is_err() {
-        warn!(
-            "failed to check dimensions for: {}, error: {}",
-            image.display(),
-            dimensions.unwrap_err()
-        );
-        return false;
-    }
+    let dimensions = match cache.get_dims(image) {
+        Ok(dimensions) => dimensions,
+        Err(e) => {
+            warn!(
+                "failed to check dimensions for: {}, error: {}",
+                image.display(),
+                e
+            );
+            return false;
+        }
+    };
+
+    dims_match(dimensions, width_range, height_range)
+}
 
-    let dimensions = dimensions.unwrap();
+pub fn dims_match(
+    dimensions: (u32, u32),
+    width_range: &Option<RangeInclusive<usize>>,
+    height_range: &Option<RangeInclusive<usize>>,
+) -> bool {
     let (width, height) = (dimensions.0 as usize, dimensions.1 as usize);
 
     if let Some(width_range) = width_range {
@@ -190,22 +299,50 @@ pub fn image_score_matches(meta: &ImageMeta, score_filter: &ScoreFilter) -> bool
     score_filter.allow_unscored
 }
 
-pub fn load_image_metas(meta_file_path: &Path) -> Result<Vec<ImageMeta>> {
+/// Load metadata, resolving relative image paths against `root_images_dir`
+pub fn load_image_metas(meta_file_path: &Path, root_images_dir: &Path) -> Result<Vec<ImageMeta>> {
     let data = fs::read_to_string(meta_file_path)?;
-    let metas: Vec<ImageMeta> = serde_json::from_str(&data)?;
+    let mut metas: Vec<ImageMeta> = serde_json::from_str(&data)?;
+    for meta in metas.iter_mut().filter(|meta| meta.path.is_relative()) {
+        meta.path = root_images_dir.join(&meta.path);
+    }
+
     Ok(metas)
 }
 
+/// Apply a JSON merge patch (RFC 7396) onto `target`
+pub fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch_map) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+
+    if let Value::Object(target_map) = target {
+        for (key, value) in patch_map.iter() {
+            if value.is_null() {
+                target_map.remove(key);
+            } else {
+                apply_merge_patch(target_map.entry(key).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// Walk `base_directory` recursively, reading directories in parallel on the worker pool
 pub fn get_all_images(base_directory: &Path) -> Result<Vec<PathBuf>> {
     Ok(WalkDir::new(base_directory)
         .into_iter()
         .filter_map(Result::ok)
         .filter(is_image_file)
-        .map(|entry| entry.path().to_owned())
+        .map(|entry| entry.path())
         .collect())
 }
 
-fn is_image_file(entry: &DirEntry) -> bool {
+fn is_image_file(entry: &DirEntry<((), ())>) -> bool {
     if let Some(file_name) = entry.file_name().to_str() {
         return file_name.to_lowercase().ends_with(".gif")
             || file_name.to_lowercase().ends_with(".jpeg")
@@ -218,22 +355,18 @@ fn is_image_file(entry: &DirEntry) -> bool {
 }
 
 pub fn compute_blake3_hash(file: &Path) -> Result<String> {
-    let mut file = File::open(file)?;
     let mut hasher = blake3::Hasher::new();
-    let mut buffer = [0; 1024];
-
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-        hasher.update(&buffer[..bytes_read]);
-    }
+    hasher.update_mmap_rayon(file)?;
 
     let hash = hasher.finalize();
     Ok(hash.to_string())
 }
 
+/// Print a warning meant for the user, unlike `warn!` which is hidden at the default log level
+pub fn print_warning(message: impl Display) {
+    eprintln!("warning: {message}");
+}
+
 pub fn create_banner(text: &str) -> String {
     let center_part = format!("# {text} #\n");
 
@@ -247,11 +380,140 @@ pub fn create_banner(text: &str) -> String {
 pub fn get_image_by_path_or_id<'a>(
     identifier: &str,
     metadatas: &'a [ImageMeta],
+    root_images_dir: &Path,
 ) -> Result<Option<&'a ImageMeta>> {
+    let index = get_image_index_by_path_or_id(identifier, metadatas, root_images_dir)?;
+    Ok(index.map(|index| &metadatas[index]))
+}
+
+/// Position of the metadata matching `identifier`, looking for a matching path first and then
+/// for a matching id. Relative paths are resolved from the current directory, then from the
+/// root images directory
+pub fn get_image_index_by_path_or_id(
+    identifier: &str,
+    metadatas: &[ImageMeta],
+    root_images_dir: &Path,
+) -> Result<Option<usize>> {
     let path = Path::new(identifier);
-    if let Some(meta) = metadatas.iter().find(|m| m.path == path) {
-        return Ok(Some(meta));
+    let mut candidates = vec![path.to_path_buf()];
+    if path.is_relative() {
+        // A missing current directory only means there is nothing to match there
+        candidates.extend(env::current_dir().ok().map(|dir| dir.join(path)));
+    }
+    // Also follows symlinks and `..`, which a plain join keeps as is
+    candidates.extend(fs::canonicalize(path).ok());
+    if path.is_relative() {
+        candidates.push(root_images_dir.join(path));
     }
 
-    Ok(metadatas.iter().find(|m| m.id == identifier))
+    for candidate in candidates.iter() {
+        if let Some(index) = metadatas.iter().position(|m| m.path == *candidate) {
+            return Ok(Some(index));
+        }
+    }
+
+    Ok(metadatas.iter().position(|m| m.id == identifier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn float_ranges_must_be_finite() {
+        assert_eq!(parse_float_range("0.5").unwrap(), 0.5..=0.5);
+        assert_eq!(parse_float_range("..1.5").unwrap(), 0.0..=1.5);
+        for input in ["NaN", "inf", "-inf", "nan..1", "0..inf", "-infinity.."] {
+            assert!(parse_float_range(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn pixel_counts_must_be_finite() {
+        assert_eq!(parse_pixel_count("2M").unwrap(), 2_000_000);
+        assert_eq!(parse_pixel_count("1.5k").unwrap(), 1_500);
+        for input in ["NaN", "inf", "infM", "nank", "1e300M", "-1"] {
+            assert!(parse_pixel_count(input).is_err(), "{input}");
+        }
+    }
+
+    fn merged(target: Value, patch: Value) -> Value {
+        let mut target = target;
+        apply_merge_patch(&mut target, &patch);
+        target
+    }
+
+    #[test]
+    fn merge_patch_replaces_and_adds_members() {
+        assert_eq!(
+            merged(
+                json!({"title": "a", "width": 1}),
+                json!({"title": "b", "tags": ["x"]})
+            ),
+            json!({"title": "b", "width": 1, "tags": ["x"]})
+        );
+    }
+
+    #[test]
+    fn merge_patch_removes_null_members() {
+        assert_eq!(
+            merged(
+                json!({"title": "a", "theme": "dark"}),
+                json!({"theme": null})
+            ),
+            json!({"title": "a"})
+        );
+        assert_eq!(
+            merged(json!({"a": 1}), json!({"missing": null})),
+            json!({"a": 1})
+        );
+    }
+
+    #[test]
+    fn merge_patch_merges_nested_objects_but_replaces_arrays() {
+        assert_eq!(
+            merged(
+                json!({"luminance": {"mean": 0.1, "median": 0.2}, "tags": ["a", "b"]}),
+                json!({"luminance": {"mean": 0.5}, "tags": ["c"]})
+            ),
+            json!({"luminance": {"mean": 0.5, "median": 0.2}, "tags": ["c"]})
+        );
+    }
+
+    #[test]
+    fn merge_patch_replaces_non_object_values() {
+        assert_eq!(
+            merged(json!({"a": "b"}), json!({"a": {"c": 1}})),
+            json!({"a": {"c": 1}})
+        );
+        assert_eq!(merged(json!(["a"]), json!({"a": "b"})), json!({"a": "b"}));
+        assert_eq!(merged(json!({"a": "b"}), json!(["c"])), json!(["c"]));
+    }
+
+    #[test]
+    fn identifier_resolves_to_the_entry_with_that_path_when_ids_are_shared() {
+        let metas = vec![
+            ImageMeta::for_tests("/walls/a.png", 1, 1),
+            ImageMeta {
+                id: "/walls/a.png".to_string(),
+                ..ImageMeta::for_tests("/walls/sub/a_copy.png", 1, 1)
+            },
+        ];
+
+        let root = Path::new("/walls");
+        let index = get_image_index_by_path_or_id("/walls/sub/a_copy.png", &metas, root);
+        assert_eq!(index.unwrap(), Some(1));
+        let index = get_image_index_by_path_or_id("/walls/a.png", &metas, root);
+        assert_eq!(index.unwrap(), Some(0));
+        let index = get_image_index_by_path_or_id("/walls/missing.png", &metas, root);
+        assert_eq!(index.unwrap(), None);
+    }
+
+    #[test]
+    fn relative_identifier_resolves_from_root_images_dir() {
+        let metas = vec![ImageMeta::for_tests("/walls/sub/a.png", 1, 1)];
+        let index = get_image_index_by_path_or_id("sub/a.png", &metas, Path::new("/walls"));
+        assert_eq!(index.unwrap(), Some(0));
+    }
 }
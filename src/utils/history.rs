@@ -0,0 +1,120 @@
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{common, store};
+use crate::models::ImageMeta;
+
+/// Maximum number of entries kept in the history file
+const MAX_HISTORY_LEN: usize = 1_000;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub path: PathBuf,
+    /// Seconds since UNIX epoch
+    pub timestamp: u64,
+    /// Number of the selection the image was part of, shared by images selected together
+    pub selection: u64,
+}
+
+/// Persistent history of randomly selected images, stored in the config directory.
+///
+/// Like the metadata store, the history is locked until dropped so that concurrent selections
+/// do not lose each other's entries
+pub struct History {
+    path: PathBuf,
+    pub entries: Vec<HistoryEntry>,
+    _lock: File,
+}
+
+impl History {
+    pub fn load() -> Result<History> {
+        let path = common::get_config_dir()?.join("history.json");
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let lock = store::lock(&path)?;
+        let entries = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("ignoring invalid history file {}: {}", path.display(), e);
+                vec![]
+            }),
+            Err(_) => {
+                info!("no history file found at: {}", path.display());
+                vec![]
+            }
+        };
+
+        debug!("loaded {} history entries", entries.len());
+        Ok(History {
+            path,
+            entries,
+            _lock: lock,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let data = serde_json::to_string(&self.entries)?;
+        store::write_atomic(&self.path, data.as_bytes())?;
+        info!("saved {} history entries", self.entries.len());
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn record(&mut self, metas: &[ImageMeta]) {
+        let timestamp = now_secs();
+        let selection = self.entries.last().map_or(1, |entry| entry.selection + 1);
+        self.entries.extend(metas.iter().map(|meta| HistoryEntry {
+            id: meta.id.clone(),
+            path: meta.path.clone(),
+            timestamp,
+            selection,
+        }));
+
+        let overflow = self.entries.len().saturating_sub(MAX_HISTORY_LEN);
+        self.entries.drain(..overflow);
+    }
+
+    /// Ids of images selected in the last `last_count` selections or within `max_age`
+    pub fn recent_ids(&self, last_count: usize, max_age: Option<Duration>) -> HashSet<String> {
+        let min_timestamp =
+            max_age.map_or(u64::MAX, |age| now_secs().saturating_sub(age.as_secs()));
+
+        let mut selection_count = 0;
+        let mut previous_selection = None;
+        let mut recent_ids = HashSet::new();
+        for entry in self.entries.iter().rev() {
+            if previous_selection != Some(entry.selection) {
+                selection_count += 1;
+                previous_selection = Some(entry.selection);
+            }
+
+            if selection_count <= last_count || entry.timestamp >= min_timestamp {
+                recent_ids.insert(entry.id.clone());
+            }
+        }
+        recent_ids
+    }
+
+    /// Position in the history of the last time an image was selected, if ever. Higher is more recent
+    pub fn last_selected_index(&self, id: &str) -> Option<usize> {
+        self.entries.iter().rposition(|entry| entry.id == id)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
@@ -0,0 +1,198 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::{
+    collections::HashMap,
+    process::{Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::models::{ImageMeta, Monitor};
+
+/// Placeholders that can be used in command templates
+pub const PLACEHOLDERS: [&str; 6] = ["path", "monitor", "id", "title", "width", "height"];
+
+/// Values substituted to placeholders of a command template
+pub fn placeholder_values(
+    meta: &ImageMeta,
+    monitor: Option<&Monitor>,
+) -> HashMap<&'static str, String> {
+    let mut values = HashMap::from([
+        ("path", meta.path.display().to_string()),
+        ("id", meta.id.clone()),
+        ("title", meta.title.clone()),
+        ("width", meta.width.to_string()),
+        ("height", meta.height.to_string()),
+    ]);
+
+    if let Some(monitor) = monitor {
+        values.insert("monitor", monitor.name.clone());
+    }
+
+    values
+}
+
+/// Split a command template into arguments, then replace `{placeholder}` in each of them.
+///
+/// The command is not run through a shell: every substituted value stays within its argument,
+/// whatever characters it contains.
+pub fn render_command(template: &str, values: &HashMap<&str, String>) -> Result<Vec<String>> {
+    let words = shell_words::split(template)
+        .with_context(|| format!("invalid command template: `{template}`"))?;
+    if words.is_empty() {
+        bail!("command template is empty");
+    }
+
+    words.iter().map(|word| render_word(word, values)).collect()
+}
+
+/// Check that a command template is valid and only uses the `available` placeholders
+pub fn check_template(template: &str, available: &[&str]) -> Result<()> {
+    let values: HashMap<&str, String> = available
+        .iter()
+        .map(|name| (*name, String::new()))
+        .collect();
+    render_command(template, &values).map(|_| ())
+}
+
+fn render_word(word: &str, values: &HashMap<&str, String>) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = word;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("unclosed placeholder in: `{word}`"))?;
+
+        let name = &rest[start + 1..start + end];
+        match values.get(name) {
+            Some(value) => rendered.push_str(value),
+            None if PLACEHOLDERS.contains(&name) => bail!("`{{{}}}` is not available here", name),
+            None => bail!(
+                "unknown placeholder `{{{}}}`, expected one of: {}",
+                name,
+                PLACEHOLDERS.map(|p| format!("{{{p}}}")).join(", ")
+            ),
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Run a rendered command, killing it if it does not exit within `timeout`
+pub fn run_command(args: &[String], timeout: Duration) -> Result<ExitStatus> {
+    let (program, program_args) = args.split_first().context("command should not be empty")?;
+
+    info!("running: {:?}", args);
+    let mut child = Command::new(program)
+        .args(program_args)
+        .spawn()
+        .with_context(|| format!("failed to run command: `{program}`"))?;
+
+    let started_at = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            debug!("command exited with: {status}");
+            return Ok(status);
+        }
+
+        if started_at.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            bail!(
+                "command timed out after {}: `{}`",
+                humantime::format_duration(timeout),
+                shell_words::join(args)
+            );
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    fn values() -> HashMap<&'static str, String> {
+        let meta = ImageMeta {
+            title: "it's \"quoted\"".to_string(),
+            ..ImageMeta::for_tests("/walls/my wall $HOME;rm.png", 1920, 1080)
+        };
+        placeholder_values(&meta, None)
+    }
+
+    /// Write a shell script to a temporary file named after the test, and the command running
+    /// it. Running it through `sh` avoids executing a file that may still be open for writing
+    fn stub_script(test_name: &str, body: &str) -> (PathBuf, [String; 2]) {
+        let path =
+            std::env::temp_dir().join(format!("kanumi-{}-{test_name}.sh", std::process::id()));
+        fs::write(&path, format!("{body}\n")).unwrap();
+        let command = ["sh".to_string(), path.display().to_string()];
+        (path, command)
+    }
+
+    #[test]
+    fn substituted_values_stay_within_their_argument() {
+        let command = render_command("feh --bg-fill {path}", &values()).unwrap();
+        assert_eq!(command, ["feh", "--bg-fill", "/walls/my wall $HOME;rm.png"]);
+
+        let command = render_command("notify 'now: {title}' \"{width}x{height}\"", &values());
+        assert_eq!(
+            command.unwrap(),
+            ["notify", "now: it's \"quoted\"", "1920x1080"]
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        let error = render_command("echo {nope}", &values()).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("unknown placeholder `{nope}`"));
+
+        let error = render_command("echo {path", &values()).unwrap_err();
+        assert!(error.to_string().starts_with("unclosed placeholder"));
+    }
+
+    #[test]
+    fn unavailable_placeholders_are_rejected() {
+        let error = render_command("swww img -o {monitor} {path}", &values()).unwrap_err();
+        assert_eq!(error.to_string(), "`{monitor}` is not available here");
+
+        assert!(check_template("echo {path} {monitor}", &["path"]).is_err());
+        assert!(check_template("echo {path} {monitor}", &PLACEHOLDERS).is_ok());
+    }
+
+    #[test]
+    fn empty_or_malformed_templates_are_rejected() {
+        assert!(render_command("", &values()).is_err());
+        assert!(render_command("echo 'unterminated", &values()).is_err());
+    }
+
+    #[test]
+    fn exit_code_of_stub_script_is_returned() {
+        let (script, command) = stub_script("exit_code_of_stub_script_is_returned", "exit 3");
+
+        let status = run_command(&command, Duration::from_secs(5)).unwrap();
+        assert_eq!(status.code(), Some(3));
+        fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn stub_script_is_killed_after_timeout() {
+        let (script, command) = stub_script("stub_script_is_killed_after_timeout", "exec sleep 5");
+
+        let started_at = Instant::now();
+        let error = run_command(&command, Duration::from_millis(200)).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("command timed out after 200ms"));
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        fs::remove_file(script).unwrap();
+    }
+}
@@ -1 +1,7 @@
+pub mod analysis;
+pub mod cache;
 pub mod common;
+pub mod history;
+pub mod hook;
+pub mod progress;
+pub mod store;
@@ -0,0 +1,33 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Progress of a step processing files in parallel, printed as `[done/total] step: path` lines
+pub struct Progress {
+    step: &'static str,
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl Progress {
+    pub fn new(step: &'static str, total: usize) -> Progress {
+        Progress {
+            step,
+            total,
+            done: AtomicUsize::new(0),
+        }
+    }
+
+    /// Report that `file` was processed. Can be called from several threads at once
+    pub fn tick(&self, file: &Path) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!(
+            "[{}/{}] {}: {}",
+            done,
+            self.total,
+            self.step,
+            file.display()
+        );
+    }
+}
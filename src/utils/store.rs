@@ -0,0 +1,124 @@
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::models::ImageMeta;
+
+/// Exclusive handle on the metadata file.
+///
+/// An advisory lock is taken on a `.lock` file next to the metadata file and held until the
+/// store is dropped, so read-modify-write cycles from concurrent kanumi processes cannot
+/// overwrite each other.
+///
+/// Image paths are stored relative to the root images directory, so that the metadata file
+/// keeps working wherever the library is mounted.
+pub struct MetadataStore {
+    path: PathBuf,
+    root_images_dir: PathBuf,
+    _lock: File,
+}
+
+impl MetadataStore {
+    pub fn open(path: &Path, root_images_dir: &Path) -> Result<MetadataStore> {
+        Ok(MetadataStore {
+            path: path.to_path_buf(),
+            root_images_dir: root_images_dir.to_path_buf(),
+            _lock: lock(path)?,
+        })
+    }
+
+    pub fn load(&self) -> Result<Vec<ImageMeta>> {
+        super::common::load_image_metas(&self.path, &self.root_images_dir)
+    }
+
+    pub fn save(&self, metas: &[ImageMeta]) -> Result<()> {
+        // Images outside of the root images directory keep their absolute path
+        let stored_metas: Vec<ImageMeta> = metas
+            .iter()
+            .cloned()
+            .map(|mut meta| {
+                if let Ok(relative_path) = meta.path.strip_prefix(&self.root_images_dir) {
+                    meta.path = relative_path.to_path_buf();
+                }
+                meta
+            })
+            .collect();
+        let data = serde_json::to_string(&stored_metas)?;
+
+        if self.path.exists() {
+            let backup_path = with_suffix(&self.path, "bak");
+            fs::copy(&self.path, &backup_path).with_context(|| {
+                format!("failed to back up metadata to: {}", backup_path.display())
+            })?;
+            debug!("previous metadata backed up to: {}", backup_path.display());
+        }
+
+        write_atomic(&self.path, data.as_bytes())?;
+        info!(
+            "saved {} metadatas to: {}",
+            metas.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Take an advisory lock on a `.lock` file next to `path`, waiting for other kanumi processes
+/// to release it. The lock is released when the returned file is dropped
+pub fn lock(path: &Path) -> Result<File> {
+    let lock_path = with_suffix(path, "lock");
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("failed to open lock file: {}", lock_path.display()))?;
+
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            warn!(
+                "{} is locked by another process, waiting...",
+                path.display()
+            );
+            lock.lock()?;
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+
+    debug!("acquired lock: {}", lock_path.display());
+    Ok(lock)
+}
+
+/// Write `data` to a temporary file, fsync it and rename it over `path`
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    // Unique per process, so that concurrent writers never share a temporary file
+    let tmp_path = with_suffix(path, &format!("{}.tmp", std::process::id()));
+
+    let mut tmp_file = File::create(&tmp_path)
+        .with_context(|| format!("failed to create temp file: {}", tmp_path.display()))?;
+    tmp_file.write_all(data)?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to replace file: {}", path.display()))?;
+
+    // Persist the rename itself, not only the file content
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.as_os_str().to_owned();
+    file_name.push(OsString::from(format!(".{suffix}")));
+    PathBuf::from(file_name)
+}